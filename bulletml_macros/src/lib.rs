@@ -0,0 +1,427 @@
+//! Compile-time embedding of BulletML documents.
+//!
+//! The [bulletml!](macro.bulletml.html) macro parses a document with the
+//! [BulletMLParser](../bulletml/parse/struct.BulletMLParser.html) while compiling, so that any
+//! error in the document fails the build, and produces a ready
+//! [BulletML](../bulletml/struct.BulletML.html) at runtime without reading any file.
+
+use bulletml::errors::ParseError;
+use bulletml::parse::BulletMLParser;
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{Ident, LitBool, LitStr, Token};
+
+/// Embeds a BulletML document and evaluates to its parsed
+/// [BulletML](../bulletml/struct.BulletML.html).
+///
+/// The argument is either the path of the document, relative to the directory of the crate
+/// manifest, or the XML document itself when it starts with `<`. Imported documents are resolved
+/// relative to the document importing them, or to the crate manifest directory for the imports of
+/// inline XML, and embedded as well.
+///
+/// The document can be followed by options of the
+/// [BulletMLParser](../bulletml/parse/struct.BulletMLParser.html), which are used both at compile
+/// time and at runtime:
+///
+/// - `variables = ["name", ...]`, `functions = ["name", ...]` and
+///   `custom_elements = ["name", ...]` register application variables, functions and elements,
+/// - `allow_unguarded_recursion = true` and `validate_structure = true` set the corresponding
+///   checks.
+///
+/// ```
+/// use bulletml_macros::bulletml;
+///
+/// let bml = bulletml!(
+///     r#"<bulletml>
+///         <action label="top">
+///             <fire><bullet /></fire>
+///         </action>
+///     </bulletml>"#
+/// );
+/// assert!(bml.action_refs.contains_key("top"));
+///
+/// let bml = bulletml!(
+///     r#"<bulletml>
+///         <action label="top">
+///             <shake><param>$hp</param></shake>
+///             <wait>angleTo($hp, 0) + 1</wait>
+///         </action>
+///     </bulletml>"#,
+///     variables = ["hp"],
+///     functions = ["angleTo"],
+///     custom_elements = ["shake"],
+/// );
+/// assert!(bml.action_refs.contains_key("top"));
+/// ```
+///
+/// Errors in the document are reported at compile time:
+///
+/// ```compile_fail
+/// use bulletml_macros::bulletml;
+///
+/// // error: inline document: Unresolved label shoot in element actionRef at position 2:29
+/// let bml = bulletml!(
+///     r#"<bulletml>
+///         <action label="top"><actionRef label="shoot" /></action>
+///     </bulletml>"#
+/// );
+/// ```
+#[proc_macro]
+pub fn bulletml(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand(input.into())
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Arguments of the macro: the document and the parser options.
+struct Input {
+    document: LitStr,
+    variables: Vec<LitStr>,
+    functions: Vec<LitStr>,
+    custom_elements: Vec<LitStr>,
+    allow_unguarded_recursion: bool,
+    validate_structure: bool,
+}
+
+impl Parse for Input {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut parsed = Input {
+            document: input.parse()?,
+            variables: Vec::new(),
+            functions: Vec::new(),
+            custom_elements: Vec::new(),
+            allow_unguarded_recursion: false,
+            validate_structure: false,
+        };
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let option = input.parse::<Ident>()?;
+            input.parse::<Token![=]>()?;
+            match option.to_string().as_str() {
+                "variables" => parsed.variables = Input::parse_names(input)?,
+                "functions" => parsed.functions = Input::parse_names(input)?,
+                "custom_elements" => parsed.custom_elements = Input::parse_names(input)?,
+                "allow_unguarded_recursion" => {
+                    parsed.allow_unguarded_recursion = input.parse::<LitBool>()?.value
+                }
+                "validate_structure" => parsed.validate_structure = input.parse::<LitBool>()?.value,
+                _ => {
+                    return Err(syn::Error::new(
+                        option.span(),
+                        format!("unknown option {}", option),
+                    ))
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Input {
+    fn parse_names(input: ParseStream) -> syn::Result<Vec<LitStr>> {
+        let content;
+        syn::bracketed!(content in input);
+        Ok(Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?
+            .into_iter()
+            .collect())
+    }
+
+    /// Creates the parser with the options.
+    fn parser(&self) -> BulletMLParser {
+        let mut parser = BulletMLParser::new()
+            .allow_unguarded_recursion(self.allow_unguarded_recursion)
+            .validate_structure(self.validate_structure);
+        for name in &self.variables {
+            parser = parser.variable(&name.value());
+        }
+        for name in &self.functions {
+            parser = parser.function(&name.value());
+        }
+        for name in &self.custom_elements {
+            parser = parser.custom_element(&name.value());
+        }
+        parser
+    }
+
+    /// Generates the creation of the same parser as [parser](#method.parser).
+    fn parser_tokens(&self) -> TokenStream {
+        let allow_unguarded_recursion = self.allow_unguarded_recursion;
+        let validate_structure = self.validate_structure;
+        let variables = &self.variables;
+        let functions = &self.functions;
+        let custom_elements = &self.custom_elements;
+        quote! {
+            ::bulletml::parse::BulletMLParser::new()
+                .allow_unguarded_recursion(#allow_unguarded_recursion)
+                .validate_structure(#validate_structure)
+                #(.variable(#variables))*
+                #(.function(#functions))*
+                #(.custom_element(#custom_elements))*
+        }
+    }
+}
+
+fn expand(input: TokenStream) -> syn::Result<TokenStream> {
+    let input = syn::parse2::<Input>(input)?;
+    let lit = &input.document;
+    let value = lit.value();
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+
+    let (file, text, dir) = if value.trim_start().starts_with('<') {
+        (None, value.clone(), manifest_dir)
+    } else {
+        let path = manifest_dir.join(&value);
+        let text = fs::read(&path)
+            .map_err(ParseError::from)
+            .and_then(|bytes| BulletMLParser::decode(&bytes).map(|text| text.into_owned()))
+            .map_err(|err| compile_error(lit.span(), &value, &err))?;
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        (Some(path), text, dir)
+    };
+
+    // Keeps the imported documents to embed them, by the path the parser resolves them to. That
+    // path is relative to the top document, so that nested documents in different directories
+    // importing the same `src` do not collide.
+    let imports = Rc::new(RefCell::new(Vec::<(String, PathBuf, String)>::new()));
+    let resolved = imports.clone();
+    let parser = input.parser().import_resolver(move |src| {
+        if let Some((_, _, text)) = resolved.borrow().iter().find(|(s, _, _)| s == src) {
+            return Ok(text.clone());
+        }
+        let path = dir.join(src);
+        let bytes = fs::read(&path)?;
+        let text = BulletMLParser::decode(&bytes)?.into_owned();
+        resolved
+            .borrow_mut()
+            .push((src.to_string(), path, text.clone()));
+        Ok(text)
+    });
+    let source = if file.is_some() {
+        value.as_str()
+    } else {
+        "inline document"
+    };
+    parser
+        .parse(&text)
+        .map_err(|err| compile_error(lit.span(), source, &err))?;
+
+    // The files are included so that the crate is rebuilt when they change.
+    let imports = imports.borrow();
+    let includes = file
+        .iter()
+        .chain(imports.iter().map(|(_, path, _)| path))
+        .map(|path| {
+            let path = path.to_string_lossy();
+            quote! { const _: &[u8] = include_bytes!(#path); }
+        });
+    let import_arms = imports.iter().map(|(src, _, text)| {
+        quote! { #src => Ok(::std::string::String::from(#text)), }
+    });
+    let parser = input.parser_tokens();
+    Ok(quote! {
+        {
+            #(#includes)*
+            #parser
+                .import_resolver(|src| match src {
+                    #(#import_arms)*
+                    _ => Err(::std::io::Error::from(::std::io::ErrorKind::NotFound).into()),
+                })
+                .parse(#text)
+                .expect("BulletML document checked at compile time")
+        }
+    })
+}
+
+/// Reports `err` with all its causes, since the position in nested documents is only given by
+/// the causes.
+fn compile_error(span: Span, source: &str, err: &dyn Error) -> syn::Error {
+    let mut message = format!("{}: {}", source, err);
+    let mut cause = err.source();
+    while let Some(err) = cause {
+        message.push_str(&format!(": {}", err));
+        cause = err.source();
+    }
+    syn::Error::new(span, message)
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    fn expand_str(s: &str) -> syn::Result<TokenStream> {
+        expand(quote! { #s })
+    }
+
+    #[test]
+    fn test_inline() {
+        let tokens =
+            expand_str(r#"<bulletml><action label="top"><wait>1</wait></action></bulletml>"#)
+                .unwrap()
+                .to_string();
+        assert!(tokens.contains("BulletMLParser :: new ()"));
+        assert!(!tokens.contains("include_bytes"));
+    }
+
+    #[test]
+    fn test_inline_error() {
+        let err =
+            expand_str(r#"<bulletml><action label="top"><wait>1 +</wait></action></bulletml>"#)
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "inline document: Expression error in \"1 +\" at position 1:40: \
+             Unexpected end of expression at offset 3"
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let document = r#"<bulletml>
+    <action label="top">
+        <shake><param>$hp</param></shake>
+        <wait>angleTo($hp, 0)</wait>
+        <actionRef label="top" />
+    </action>
+</bulletml>"#;
+        let err = expand_str(document).unwrap_err();
+        assert!(err.to_string().starts_with("inline document: "));
+
+        let tokens = expand(quote! {
+            #document,
+            variables = ["hp"],
+            functions = ["angleTo"],
+            custom_elements = ["shake"],
+            allow_unguarded_recursion = true,
+            validate_structure = false,
+        })
+        .unwrap()
+        .to_string();
+        for option in &[
+            ". variable (\"hp\")",
+            ". function (\"angleTo\")",
+            ". custom_element (\"shake\")",
+            ". allow_unguarded_recursion (true)",
+            ". validate_structure (false)",
+        ] {
+            assert!(tokens.contains(option), "{} in {}", option, tokens);
+        }
+
+        // Every option is needed.
+        assert!(expand(quote! {
+            #document,
+            variables = ["hp"],
+            functions = ["angleTo"],
+            custom_elements = ["shake"]
+        })
+        .is_err());
+
+        let err = expand(quote! { #document, variable = ["hp"] }).unwrap_err();
+        assert_eq!(err.to_string(), "unknown option variable");
+        assert!(expand(quote! { #document, variables = "hp" }).is_err());
+        assert!(expand(quote! { #document, validate_structure = 1 }).is_err());
+    }
+
+    #[test]
+    fn test_not_a_string() {
+        assert!(expand(quote! { 42 }).is_err());
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = std::env::temp_dir().join(format!("bulletml-macros-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.xml");
+        fs::write(
+            &main,
+            r#"<bulletml>
+    <import src="shapes.xml" as="shapes" />
+    <action label="top"><fireRef label="shapes.ring" /></action>
+</bulletml>"#,
+        )
+        .unwrap();
+        let shapes = dir.join("shapes.xml");
+        fs::write(
+            &shapes,
+            r#"<bulletml><fire label="ring"><bullet /></fire></bulletml>"#,
+        )
+        .unwrap();
+
+        let tokens = expand_str(main.to_str().unwrap()).unwrap().to_string();
+        assert_eq!(tokens.matches("include_bytes").count(), 2);
+        assert!(tokens.contains("\"shapes.xml\" =>"));
+
+        // Nested imports are relative to the importing document, and keyed by their resolved path
+        // so that the same `src` in different directories does not collide.
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::create_dir_all(dir.join("b")).unwrap();
+        for sub in &["a", "b"] {
+            fs::write(
+                dir.join(sub).join("patterns.xml"),
+                r#"<bulletml><import src="shapes.xml" as="shapes" /></bulletml>"#,
+            )
+            .unwrap();
+            fs::write(
+                dir.join(sub).join("shapes.xml"),
+                format!(
+                    r#"<bulletml><fire label="{}"><bullet /></fire></bulletml>"#,
+                    sub
+                ),
+            )
+            .unwrap();
+        }
+        let nested = dir.join("nested.xml");
+        fs::write(
+            &nested,
+            r#"<bulletml>
+    <import src="a/patterns.xml" as="a" />
+    <import src="b/patterns.xml" as="b" />
+    <import src="shapes.xml" as="shapes" />
+    <import src="./a/shapes.xml" as="again" />
+</bulletml>"#,
+        )
+        .unwrap();
+        let tokens = expand_str(nested.to_str().unwrap()).unwrap().to_string();
+        assert_eq!(tokens.matches("include_bytes").count(), 6);
+        for src in &[
+            "a/patterns.xml",
+            "a/shapes.xml",
+            "b/patterns.xml",
+            "b/shapes.xml",
+            "shapes.xml",
+        ] {
+            assert_eq!(tokens.matches(&format!("\"{}\" =>", src)).count(), 1);
+        }
+
+        fs::write(
+            &shapes,
+            r#"<bulletml><fire label="ring"><bulletRef label="missing" /></fire></bulletml>"#,
+        )
+        .unwrap();
+        let err = expand_str(main.to_str().unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}: Error in document shapes.xml imported at position 2:5: \
+                 Unresolved label shapes.missing in element bulletRef at position 1:30",
+                main.display()
+            )
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        let err = expand_str(main.to_str().unwrap()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("{}: I/O error: ", main.display())));
+    }
+}
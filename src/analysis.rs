@@ -0,0 +1,147 @@
+use crate::tree::{BulletMLExpression, BulletMLNode};
+use indextree::{Arena, NodeId};
+use std::collections::{HashMap, HashSet};
+
+/// Computes the highest parameter (`$1`, `$2`, ...) used in the subtree of `id`.
+///
+/// The targets of the references found in the subtree are not visited because they receive
+/// their own parameters. The `<param>` children of those references are visited though since they
+/// are evaluated with the parameters of the subtree.
+pub(crate) fn max_parameter(arena: &Arena<BulletMLNode>, id: NodeId) -> usize {
+    id.descendants(arena)
+        .flat_map(|child| arena[child].get().expressions())
+        .map(BulletMLExpression::max_parameter)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Counts the `<param>` children of a reference node.
+pub(crate) fn parameter_count(arena: &Arena<BulletMLNode>, ref_id: NodeId) -> usize {
+    ref_id
+        .children(arena)
+        .filter(|child| matches!(arena[*child].get(), BulletMLNode::Param(..)))
+        .count()
+}
+
+/// Finds a reference which recurses into its own label without any guaranteed wait on the way,
+/// which would make the runner loop forever.
+///
+/// Only `actionRef` can close such a cycle: a fire contains no action and the actions of a bullet
+/// are run by a new runner. A wait is guaranteed when its number of frames is a constant of at
+/// least 1.
+///
+/// Returns the reference node closing the first cycle found.
+pub(crate) fn find_unguarded_cycle(
+    arena: &Arena<BulletMLNode>,
+    action_refs: &HashMap<String, NodeId>,
+) -> Option<NodeId> {
+    let mut labelled: Vec<NodeId> = action_refs.values().copied().collect();
+    labelled.sort();
+
+    let mut scanner = WaitScanner {
+        arena,
+        action_refs,
+        waits: HashSet::new(),
+    };
+    // Least fixpoint: a label waits if it does so when assuming the labels not known yet to wait
+    // do not.
+    loop {
+        let mut changed = false;
+        for id in &labelled {
+            if !scanner.waits.contains(id) && scanner.scan(*id, &mut Vec::new()) {
+                scanner.waits.insert(*id);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let edges: HashMap<NodeId, Vec<(NodeId, NodeId)>> = labelled
+        .iter()
+        .map(|id| {
+            let mut edges = Vec::new();
+            scanner.scan(*id, &mut edges);
+            (*id, edges)
+        })
+        .collect();
+
+    let mut visited = HashSet::new();
+    for id in &labelled {
+        if let Some(ref_id) = find_cycle_from(*id, &edges, &mut visited) {
+            return Some(ref_id);
+        }
+    }
+    None
+}
+
+/// Depth-first search of a cycle from `id`. It does not recurse so that long chains of labels
+/// cannot overflow the stack.
+fn find_cycle_from(
+    id: NodeId,
+    edges: &HashMap<NodeId, Vec<(NodeId, NodeId)>>,
+    visited: &mut HashSet<NodeId>,
+) -> Option<NodeId> {
+    if !visited.insert(id) {
+        return None;
+    }
+    // Labels of the current path, with the index of the next edge to follow from each of them.
+    let mut path = vec![(id, 0)];
+    let mut on_path = HashSet::new();
+    on_path.insert(id);
+    while let Some((id, next)) = path.last_mut() {
+        let id = *id;
+        let edge = edges.get(&id).and_then(|edges| edges.get(*next));
+        *next += 1;
+        match edge {
+            Some((ref_id, target)) => {
+                if on_path.contains(target) {
+                    return Some(*ref_id);
+                }
+                if visited.insert(*target) {
+                    path.push((*target, 0));
+                    on_path.insert(*target);
+                }
+            }
+            None => {
+                path.pop();
+                on_path.remove(&id);
+            }
+        }
+    }
+    None
+}
+
+struct WaitScanner<'a> {
+    arena: &'a Arena<BulletMLNode>,
+    action_refs: &'a HashMap<String, NodeId>,
+    waits: HashSet<NodeId>,
+}
+
+impl<'a> WaitScanner<'a> {
+    /// Tells whether running `id` guarantees a wait, collecting the `(reference, target)` pairs
+    /// met before that wait.
+    fn scan(&self, id: NodeId, edges: &mut Vec<(NodeId, NodeId)>) -> bool {
+        match self.arena[id].get() {
+            BulletMLNode::Action(..) => {
+                id.children(self.arena).any(|child| self.scan(child, edges))
+            }
+            BulletMLNode::Repeat => id
+                .children(self.arena)
+                .find(|child| self.arena[*child].get().match_any_action().is_some())
+                .map(|action| self.scan(action, edges))
+                .unwrap_or(false),
+            BulletMLNode::Wait(frames) => matches!(frames.constant(), Some(frames) if frames >= 1.),
+            BulletMLNode::ActionRef(label) => {
+                if let Some(target) = self.action_refs.get(label) {
+                    edges.push((id, *target));
+                    self.waits.contains(target)
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+}
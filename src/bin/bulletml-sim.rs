@@ -0,0 +1,456 @@
+use bulletml::parse::BulletMLParser;
+use bulletml::world::{BulletId, BulletWorld, WorldHooks};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::sync::Arc;
+
+const USAGE: &str = "Usage: bulletml-sim [OPTIONS] FILE
+
+Runs the top actions of the BulletML document FILE without display and writes the state of every
+bullet at the end of each frame.
+
+Bullets are numbered in the order they appear, starting with the emitter which runs the top actions
+and is bullet 0. Numbers are never reused.
+
+Options:
+    --frames N          Number of frames to run [default: 60]
+    --rank RANK         Rank, between 0 and 1 [default: 0.5]
+    --seed SEED         Seed of the random number generator
+    --emitter X,Y       Position of the emitter [default: 0,0]
+    --target X,Y        Position the bullets aim at [default: 0,100]
+    --format FORMAT     Output format, csv or json (one object per line) [default: csv]
+    --output PATH       Output file [default: standard output]
+    -h, --help          Prints this help";
+
+#[derive(Debug, PartialEq)]
+enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    file: String,
+    frames: u32,
+    rank: f64,
+    seed: Option<u64>,
+    emitter: (f64, f64),
+    target: (f64, f64),
+    format: Format,
+    output: Option<String>,
+}
+
+/// Parses the command line arguments, without the program name. Returns `None` if the help is
+/// requested.
+fn parse_args<I>(args: I) -> Result<Option<Options>, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut file = None;
+    let mut options = Options {
+        file: String::new(),
+        frames: 60,
+        rank: 0.5,
+        seed: None,
+        emitter: (0., 0.),
+        target: (0., 100.),
+        format: Format::Csv,
+        output: None,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--frames" => options.frames = parse_value(&arg, &value()?)?,
+            "--rank" => options.rank = parse_value(&arg, &value()?)?,
+            "--seed" => options.seed = Some(parse_value(&arg, &value()?)?),
+            "--emitter" => options.emitter = parse_position(&arg, &value()?)?,
+            "--target" => options.target = parse_position(&arg, &value()?)?,
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "csv" => Format::Csv,
+                    "json" => Format::Json,
+                    format => return Err(format!("Unknown format {}", format)),
+                }
+            }
+            "--output" => options.output = Some(value()?),
+            option if option.starts_with('-') => {
+                return Err(format!("Unknown option {}", option));
+            }
+            _ if file.is_some() => return Err(format!("Unexpected argument {}", arg)),
+            _ => file = Some(arg),
+        }
+    }
+    options.file = file.ok_or_else(|| "Missing FILE".to_string())?;
+    Ok(Some(options))
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value {} for {}", value, option))
+}
+
+fn parse_position(option: &str, value: &str) -> Result<(f64, f64), String> {
+    let mut coordinates = value.splitn(2, ',');
+    match (coordinates.next(), coordinates.next()) {
+        (Some(x), Some(y)) => Ok((parse_value(option, x)?, parse_value(option, y)?)),
+        _ => Err(format!(
+            "Invalid position {} for {}, expected X,Y",
+            value, option
+        )),
+    }
+}
+
+struct Hooks {
+    rank: f64,
+    target: (f64, f64),
+}
+
+impl WorldHooks for Hooks {
+    fn aim_target(&self) -> (f64, f64) {
+        self.target
+    }
+
+    fn rank(&self) -> f64 {
+        self.rank
+    }
+}
+
+/// Runs the simulation of `bml` and writes the bullets to `out`.
+///
+/// Bullets are numbered in the order they appear, and written in that order, so that the output
+/// does not depend on how the world stores them. The emitter is bullet 0.
+fn simulate<W: Write>(
+    options: &Options,
+    bml: bulletml::BulletML,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    let mut world = BulletWorld::new(Hooks {
+        rank: options.rank,
+        target: options.target,
+    });
+    if let Some(seed) = options.seed {
+        world = world.seed(seed);
+    }
+    let (x, y) = options.emitter;
+    world.spawn(Arc::new(bml), x, y);
+
+    let mut numbers = HashMap::<BulletId, usize>::new();
+    let mut next_number = 0;
+    if options.format == Format::Csv {
+        writeln!(out, "frame,id,x,y,direction,speed")?;
+    }
+    for frame in 0..options.frames {
+        world.step()?;
+        let mut rows = world
+            .bullets()
+            .map(|(id, bullet)| {
+                let number = *numbers.entry(id).or_insert_with(|| {
+                    next_number += 1;
+                    next_number - 1
+                });
+                let (x, y) = bullet.position();
+                (number, [x, y, bullet.direction(), bullet.speed()])
+            })
+            .collect::<Vec<_>>();
+        rows.sort_by_key(|(number, _)| *number);
+        for (number, values) in rows {
+            match options.format {
+                Format::Csv => {
+                    write!(out, "{},{}", frame, number)?;
+                    for value in &values {
+                        write!(out, ",{}", value)?;
+                    }
+                    writeln!(out)?;
+                }
+                Format::Json => {
+                    write!(out, r#"{{"frame":{},"id":{}"#, frame, number)?;
+                    for (name, value) in ["x", "y", "direction", "speed"].iter().zip(&values) {
+                        // JSON has no representation of infinite numbers.
+                        if value.is_finite() {
+                            write!(out, r#","{}":{}"#, name, value)?;
+                        } else {
+                            write!(out, r#","{}":null"#, name)?;
+                        }
+                    }
+                    writeln!(out, "}}")?;
+                }
+            }
+        }
+        // Bullets which are removed never come back.
+        numbers.retain(|id, _| world.get(*id).is_some());
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn Error>> {
+    let bml = BulletMLParser::new().parse_file(&options.file)?;
+    match &options.output {
+        Some(path) => simulate(options, bml, &mut BufWriter::new(fs::File::create(path)?)),
+        None => simulate(options, bml, &mut BufWriter::new(io::stdout().lock())),
+    }
+}
+
+fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("bulletml-sim: {}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = run(&options) {
+        eprint!("bulletml-sim: {}", err);
+        let mut source = err.source();
+        while let Some(err) = source {
+            eprint!(": {}", err);
+            source = err.source();
+        }
+        eprintln!();
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            args(&["file.xml"]),
+            Ok(Some(Options {
+                file: "file.xml".to_string(),
+                frames: 60,
+                rank: 0.5,
+                seed: None,
+                emitter: (0., 0.),
+                target: (0., 100.),
+                format: Format::Csv,
+                output: None,
+            }))
+        );
+        assert_eq!(
+            args(&[
+                "--frames",
+                "10",
+                "--rank",
+                "1",
+                "--seed",
+                "42",
+                "--emitter",
+                "1,-2",
+                "--target",
+                "3.5,4",
+                "--format",
+                "json",
+                "--output",
+                "out.jsonl",
+                "file.xml",
+            ]),
+            Ok(Some(Options {
+                file: "file.xml".to_string(),
+                frames: 10,
+                rank: 1.,
+                seed: Some(42),
+                emitter: (1., -2.),
+                target: (3.5, 4.),
+                format: Format::Json,
+                output: Some("out.jsonl".to_string()),
+            }))
+        );
+        assert_eq!(args(&["file.xml", "--help"]), Ok(None));
+        assert_eq!(args(&[]), Err("Missing FILE".to_string()));
+        assert_eq!(
+            args(&["file.xml", "--frames"]),
+            Err("Missing value for --frames".to_string())
+        );
+        assert_eq!(
+            args(&["--frames", "-1", "file.xml"]),
+            Err("Invalid value -1 for --frames".to_string())
+        );
+        assert_eq!(
+            args(&["--emitter", "1", "file.xml"]),
+            Err("Invalid position 1 for --emitter, expected X,Y".to_string())
+        );
+        assert_eq!(
+            args(&["--format", "xml", "file.xml"]),
+            Err("Unknown format xml".to_string())
+        );
+        assert_eq!(
+            args(&["--verbose", "file.xml"]),
+            Err("Unknown option --verbose".to_string())
+        );
+        assert_eq!(
+            args(&["file.xml", "other.xml"]),
+            Err("Unexpected argument other.xml".to_string())
+        );
+    }
+
+    fn simulate_to_string(format: Format) -> String {
+        simulate_text_to_string(
+            r##"<?xml version="1.0" ?>
+<!DOCTYPE bulletml SYSTEM "../bulletml.dtd">
+<bulletml>
+<action label="top">
+    <repeat>
+        <times>2</times>
+        <action>
+            <fire>
+                <direction type="absolute">0</direction>
+                <speed>$rank * 2</speed>
+                <bullet />
+            </fire>
+            <wait>1</wait>
+        </action>
+    </repeat>
+</action>
+</bulletml>"##,
+            format,
+        )
+    }
+
+    fn simulate_text_to_string(text: &str, format: Format) -> String {
+        let bml = BulletMLParser::new().parse(text).unwrap();
+        let mut options = args(&["--frames", "3", "--rank", "1", "file.xml"])
+            .unwrap()
+            .unwrap();
+        options.format = format;
+        let mut out = Vec::new();
+        simulate(&options, bml, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_simulate_csv() {
+        assert_eq!(
+            simulate_to_string(Format::Csv),
+            "frame,id,x,y,direction,speed
+0,0,0,0,0,0
+0,1,0,0,0,2
+1,0,0,0,0,0
+1,1,0,-2,0,2
+1,2,0,0,0,2
+2,0,0,0,0,0
+2,1,0,-4,0,2
+2,2,0,-2,0,2
+"
+        );
+    }
+
+    #[test]
+    fn test_simulate_json() {
+        assert_eq!(
+            simulate_to_string(Format::Json),
+            r#"{"frame":0,"id":0,"x":0,"y":0,"direction":0,"speed":0}
+{"frame":0,"id":1,"x":0,"y":0,"direction":0,"speed":2}
+{"frame":1,"id":0,"x":0,"y":0,"direction":0,"speed":0}
+{"frame":1,"id":1,"x":0,"y":-2,"direction":0,"speed":2}
+{"frame":1,"id":2,"x":0,"y":0,"direction":0,"speed":2}
+{"frame":2,"id":0,"x":0,"y":0,"direction":0,"speed":0}
+{"frame":2,"id":1,"x":0,"y":-4,"direction":0,"speed":2}
+{"frame":2,"id":2,"x":0,"y":-2,"direction":0,"speed":2}
+"#
+        );
+    }
+
+    #[test]
+    fn test_simulate_numbers() {
+        let output = simulate_text_to_string(
+            r##"<?xml version="1.0" ?>
+<!DOCTYPE bulletml SYSTEM "../bulletml.dtd">
+<bulletml>
+<action label="top">
+    <repeat>
+        <times>3</times>
+        <action>
+            <fire>
+                <bullet>
+                    <action>
+                        <vanish />
+                    </action>
+                </bullet>
+            </fire>
+            <fire>
+                <bullet />
+            </fire>
+            <wait>1</wait>
+        </action>
+    </repeat>
+</action>
+</bulletml>"##,
+            Format::Csv,
+        );
+        let rows = output
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let mut columns = line.split(',');
+                (columns.next().unwrap(), columns.next().unwrap())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                ("0", "0"),
+                ("0", "1"),
+                ("0", "2"),
+                ("1", "0"),
+                ("1", "2"),
+                ("1", "3"),
+                ("1", "4"),
+                ("2", "0"),
+                ("2", "2"),
+                ("2", "4"),
+                ("2", "5"),
+                ("2", "6"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_simulate_seed() {
+        let simulate_seed = |seed: &str| {
+            let bml = BulletMLParser::new()
+                .parse(
+                    r##"<?xml version="1.0" ?>
+<!DOCTYPE bulletml SYSTEM "../bulletml.dtd">
+<bulletml>
+<action label="top">
+    <fire>
+        <direction type="absolute">$rand * 360</direction>
+        <bullet />
+    </fire>
+</action>
+</bulletml>"##,
+                )
+                .unwrap();
+            let options = args(&["--frames", "1", "--seed", seed, "file.xml"])
+                .unwrap()
+                .unwrap();
+            let mut out = Vec::new();
+            simulate(&options, bml, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(simulate_seed("2"), simulate_seed("2"));
+        assert_ne!(simulate_seed("2"), simulate_seed("3"));
+    }
+}
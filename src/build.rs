@@ -0,0 +1,949 @@
+use crate::analysis;
+use crate::dtd::{self, ContentError};
+use crate::errors::{BuildError, ReferenceKind};
+use crate::parse::BulletMLParser;
+use crate::reload::NodePath;
+use crate::tree::{
+    BulletML, BulletMLExpression, BulletMLNode, BulletMLType, DirectionType, HVType, SpeedType,
+};
+use indextree::{Arena, NodeId};
+use std::collections::HashMap;
+
+/// Expression given to a builder: either a constant or the text of an expression as it would be
+/// written in a BulletML document, e.g. `"$1 + $rank * 10"`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuilderExpression {
+    Const(f64),
+    Text(String),
+}
+
+impl From<f64> for BuilderExpression {
+    fn from(value: f64) -> Self {
+        BuilderExpression::Const(value)
+    }
+}
+
+impl From<i32> for BuilderExpression {
+    fn from(value: i32) -> Self {
+        BuilderExpression::Const(f64::from(value))
+    }
+}
+
+impl From<&str> for BuilderExpression {
+    fn from(text: &str) -> Self {
+        BuilderExpression::Text(text.to_string())
+    }
+}
+
+impl From<String> for BuilderExpression {
+    fn from(text: String) -> Self {
+        BuilderExpression::Text(text)
+    }
+}
+
+/// Builds a [BulletML](../struct.BulletML.html) document directly, without going through XML.
+///
+/// ```
+/// use bulletml::build::BulletMLBuilder;
+/// use bulletml::DirectionType;
+///
+/// let bml = BulletMLBuilder::new()
+///     .top_action(|a| {
+///         a.repeat("3 + $rank * 10", |a| {
+///             a.fire_ref("shot", |r| r.param(-10)).wait(5)
+///         })
+///     })
+///     .fire("shot", |f| {
+///         f.direction(DirectionType::Aim, "$1 + $rand * 20")
+///             .bullet(|b| b.speed(None, 2))
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// Labels and the structure of the elements are checked by [build](#method.build), with the same
+/// rules as the parser uses for documents validated against bulletml.dtd.
+pub struct BulletMLBuilder {
+    state: BuildState,
+    root: NodeId,
+    top_actions: usize,
+    allow_unguarded_recursion: bool,
+}
+
+impl BulletMLBuilder {
+    /// Creates a new builder with default capacities.
+    pub fn new() -> Self {
+        BulletMLBuilder::from_compiler(BulletMLParser::new())
+    }
+
+    /// Creates a new builder with custom capacities, see
+    /// [BulletMLParser::with_capacities](../parse/struct.BulletMLParser.html#method.with_capacities).
+    ///
+    /// `expr_capacity` is ignored, it is kept for compatibility.
+    pub fn with_capacities(refs_capacity: usize, expr_capacity: usize) -> Self {
+        let mut builder =
+            BulletMLBuilder::from_compiler(BulletMLParser::with_capacities(0, expr_capacity));
+        builder.state.bullet_refs.reserve(refs_capacity);
+        builder.state.action_refs.reserve(refs_capacity);
+        builder.state.fire_refs.reserve(refs_capacity);
+        builder
+    }
+
+    fn from_compiler(compiler: BulletMLParser) -> Self {
+        let mut arena = Arena::new();
+        let root = arena.new_node(BulletMLNode::BulletML { bml_type: None });
+        BulletMLBuilder {
+            state: BuildState {
+                arena,
+                bullet_refs: HashMap::new(),
+                action_refs: HashMap::new(),
+                fire_refs: HashMap::new(),
+                refs: Vec::new(),
+                compiler,
+                error: None,
+            },
+            root,
+            top_actions: 0,
+            allow_unguarded_recursion: false,
+        }
+    }
+
+    /// Sets the orientation of the document, which is none by default.
+    pub fn bml_type(mut self, bml_type: BulletMLType) -> Self {
+        *self.state.arena[self.root].get_mut() = BulletMLNode::BulletML {
+            bml_type: Some(bml_type),
+        };
+        self
+    }
+
+    /// Allows or rejects actions which reference themselves without a guaranteed wait, see
+    /// [BulletMLParser::allow_unguarded_recursion](../parse/struct.BulletMLParser.html#method.allow_unguarded_recursion).
+    pub fn allow_unguarded_recursion(mut self, allow: bool) -> Self {
+        self.allow_unguarded_recursion = allow;
+        self
+    }
+
+    /// Registers an application variable, see
+    /// [BulletMLParser::variable](../parse/struct.BulletMLParser.html#method.variable). It must be
+    /// registered before the expressions using it are added.
+    pub fn variable(mut self, name: &str) -> Self {
+        self.state.compiler = self.state.compiler.variable(name);
+        self
+    }
+
+    /// Registers an application function, see
+    /// [BulletMLParser::function](../parse/struct.BulletMLParser.html#method.function). It must be
+    /// registered before the expressions using it are added.
+    pub fn function(mut self, name: &str) -> Self {
+        self.state.compiler = self.state.compiler.function(name);
+        self
+    }
+
+    /// Adds a "top" action, i.e. an action run by
+    /// [Runner::new](../struct.Runner.html#method.new). The top actions are labelled `top1`,
+    /// `top2` and so on.
+    pub fn top_action<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(ActionBuilder) -> ActionBuilder,
+    {
+        self.top_actions += 1;
+        let label = format!("top{}", self.top_actions);
+        self.action(&label, f)
+    }
+
+    /// Adds a labelled action which can be referenced with `actionRef`.
+    pub fn action<F>(mut self, label: &str, f: F) -> Self
+    where
+        F: FnOnce(ActionBuilder) -> ActionBuilder,
+    {
+        self.state = ActionBuilder::build(self.state, self.root, Some(label), f);
+        self
+    }
+
+    /// Adds a labelled bullet which can be referenced with `bulletRef`.
+    pub fn bullet<F>(mut self, label: &str, f: F) -> Self
+    where
+        F: FnOnce(BulletBuilder) -> BulletBuilder,
+    {
+        self.state = BulletBuilder::build(self.state, self.root, Some(label), f);
+        self
+    }
+
+    /// Adds a labelled fire which can be referenced with `fireRef`.
+    pub fn fire<F>(mut self, label: &str, f: F) -> Self
+    where
+        F: FnOnce(FireBuilder) -> FireBuilder,
+    {
+        self.state = FireBuilder::build(self.state, self.root, Some(label), f);
+        self
+    }
+
+    /// Checks the document and returns it.
+    pub fn build(self) -> Result<BulletML, BuildError> {
+        let BuildState {
+            arena,
+            bullet_refs,
+            action_refs,
+            fire_refs,
+            refs,
+            compiler: _,
+            error,
+        } = self.state;
+        if let Some(err) = error {
+            return Err(err);
+        }
+        let bml = BulletML {
+            arena,
+            root: self.root,
+            bullet_refs,
+            action_refs,
+            fire_refs,
+            spans: HashMap::new(),
+        };
+        BulletMLBuilder::check_structure(&bml)?;
+        BulletMLBuilder::check_refs(&bml, &refs)?;
+        if !self.allow_unguarded_recursion {
+            if let Some(ref_id) = analysis::find_unguarded_cycle(&bml.arena, &bml.action_refs) {
+                if let Some((kind, label)) = bml.arena[ref_id].get().match_ref() {
+                    return Err(BuildError::new_unguarded_recursion(
+                        label.to_string(),
+                        kind,
+                        path(&bml, ref_id),
+                    ));
+                }
+            }
+        }
+        Ok(bml)
+    }
+
+    fn check_structure(bml: &BulletML) -> Result<(), BuildError> {
+        for id in bml.root.descendants(&bml.arena) {
+            let element = element_name(bml.arena[id].get());
+            let res = dtd::check_content(
+                element,
+                id.children(&bml.arena)
+                    .map(|child| (element_name(bml.arena[child].get()), child)),
+            );
+            match res {
+                Ok(()) => {}
+                Err(ContentError::Missing { expected }) => {
+                    return Err(BuildError::new_missing_element(
+                        expected,
+                        element.to_string(),
+                        path(bml, id),
+                    ))
+                }
+                Err(ContentError::Misplaced { child, pos }) => {
+                    return Err(BuildError::new_misplaced_element(
+                        child.to_string(),
+                        element.to_string(),
+                        path(bml, pos),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every reference points at an existing label and passes enough parameters.
+    fn check_refs(bml: &BulletML, refs: &[NodeId]) -> Result<(), BuildError> {
+        for id in refs {
+            if let Some((kind, label)) = bml.arena[*id].get().match_ref() {
+                let labels = match kind {
+                    ReferenceKind::Bullet => &bml.bullet_refs,
+                    ReferenceKind::Action => &bml.action_refs,
+                    ReferenceKind::Fire => &bml.fire_refs,
+                };
+                let target = labels.get(label).ok_or_else(|| {
+                    BuildError::new_unresolved_reference(label.to_string(), kind, path(bml, *id))
+                })?;
+                let expected = analysis::max_parameter(&bml.arena, *target);
+                let found = analysis::parameter_count(&bml.arena, *id);
+                if found < expected {
+                    return Err(BuildError::new_parameter_count(
+                        label.to_string(),
+                        kind,
+                        expected,
+                        found,
+                        path(bml, *id),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for BulletMLBuilder {
+    fn default() -> Self {
+        BulletMLBuilder::new()
+    }
+}
+
+/// Document under construction, passed from builder to builder.
+struct BuildState {
+    arena: Arena<BulletMLNode>,
+    bullet_refs: HashMap<String, NodeId>,
+    action_refs: HashMap<String, NodeId>,
+    fire_refs: HashMap<String, NodeId>,
+    refs: Vec<NodeId>,
+    compiler: BulletMLParser,
+    /// First error found while building, reported by `build`.
+    error: Option<BuildError>,
+}
+
+impl BuildState {
+    fn append(&mut self, parent: NodeId, node: BulletMLNode) -> NodeId {
+        let id = self.arena.new_node(node);
+        parent.append(id, &mut self.arena);
+        id
+    }
+
+    /// Appends a labelled definition and registers its label.
+    fn append_definition(
+        &mut self,
+        parent: NodeId,
+        label: Option<&str>,
+        kind: ReferenceKind,
+    ) -> NodeId {
+        let owned_label = label.map(str::to_string);
+        let node = match kind {
+            ReferenceKind::Bullet => BulletMLNode::Bullet(owned_label),
+            ReferenceKind::Action => BulletMLNode::Action(owned_label),
+            ReferenceKind::Fire => BulletMLNode::Fire(owned_label),
+        };
+        let id = self.append(parent, node);
+        if let Some(label) = label {
+            let labels = match kind {
+                ReferenceKind::Bullet => &mut self.bullet_refs,
+                ReferenceKind::Action => &mut self.action_refs,
+                ReferenceKind::Fire => &mut self.fire_refs,
+            };
+            if labels.insert(label.to_string(), id).is_some() {
+                let element = element_name(self.arena[id].get());
+                self.fail(BuildError::new_duplicate_label(
+                    label.to_string(),
+                    element.to_string(),
+                ));
+            }
+        }
+        id
+    }
+
+    fn expression(&mut self, expr: BuilderExpression) -> BulletMLExpression {
+        match expr {
+            BuilderExpression::Const(value) => BulletMLExpression::Const(value),
+            BuilderExpression::Text(text) => {
+                let text = text.trim();
+                match self.compiler.expression(text, None) {
+                    Ok(expr) => expr,
+                    Err(err) => {
+                        self.fail(BuildError::new_expression(err, text.to_string()));
+                        BulletMLExpression::Const(0.)
+                    }
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, err: BuildError) {
+        if self.error.is_none() {
+            self.error = Some(err);
+        }
+    }
+}
+
+/// Builds the content of an `<action>`.
+pub struct ActionBuilder {
+    state: BuildState,
+    id: NodeId,
+}
+
+impl ActionBuilder {
+    fn build<F>(mut state: BuildState, parent: NodeId, label: Option<&str>, f: F) -> BuildState
+    where
+        F: FnOnce(ActionBuilder) -> ActionBuilder,
+    {
+        let id = state.append_definition(parent, label, ReferenceKind::Action);
+        f(ActionBuilder { state, id }).state
+    }
+
+    /// Adds a `<repeat>` running the action built by `f` `times` times.
+    pub fn repeat<E, F>(mut self, times: E, f: F) -> Self
+    where
+        E: Into<BuilderExpression>,
+        F: FnOnce(ActionBuilder) -> ActionBuilder,
+    {
+        let repeat = self.state.append(self.id, BulletMLNode::Repeat);
+        let times = self.state.expression(times.into());
+        self.state.append(repeat, BulletMLNode::Times(times));
+        self.state = ActionBuilder::build(self.state, repeat, None, f);
+        self
+    }
+
+    /// Adds an anonymous `<fire>`.
+    pub fn fire<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(FireBuilder) -> FireBuilder,
+    {
+        self.state = FireBuilder::build(self.state, self.id, None, f);
+        self
+    }
+
+    /// Adds a `<fireRef>` whose parameters are added by `f`.
+    pub fn fire_ref<F>(mut self, label: &str, f: F) -> Self
+    where
+        F: FnOnce(RefBuilder) -> RefBuilder,
+    {
+        self.state = RefBuilder::build(self.state, self.id, label, ReferenceKind::Fire, f);
+        self
+    }
+
+    /// Adds a `<changeSpeed>` reaching `speed` in `term` frames.
+    pub fn change_speed<T, E1, E2>(mut self, spd_type: T, spd: E1, term: E2) -> Self
+    where
+        T: Into<Option<SpeedType>>,
+        E1: Into<BuilderExpression>,
+        E2: Into<BuilderExpression>,
+    {
+        let change_speed = self.state.append(self.id, BulletMLNode::ChangeSpeed);
+        let spd = self.state.expression(spd.into());
+        self.state.append(
+            change_speed,
+            BulletMLNode::Speed {
+                spd_type: spd_type.into(),
+                spd,
+            },
+        );
+        let term = self.state.expression(term.into());
+        self.state.append(change_speed, BulletMLNode::Term(term));
+        self
+    }
+
+    /// Adds a `<changeDirection>` reaching `dir` in `term` frames.
+    pub fn change_direction<T, E1, E2>(mut self, dir_type: T, dir: E1, term: E2) -> Self
+    where
+        T: Into<Option<DirectionType>>,
+        E1: Into<BuilderExpression>,
+        E2: Into<BuilderExpression>,
+    {
+        let change_direction = self.state.append(self.id, BulletMLNode::ChangeDirection);
+        let dir = self.state.expression(dir.into());
+        self.state.append(
+            change_direction,
+            BulletMLNode::Direction {
+                dir_type: dir_type.into(),
+                dir,
+            },
+        );
+        let term = self.state.expression(term.into());
+        self.state
+            .append(change_direction, BulletMLNode::Term(term));
+        self
+    }
+
+    /// Adds an `<accel>` whose content is built by `f`.
+    pub fn accel<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(AccelBuilder) -> AccelBuilder,
+    {
+        let id = self.state.append(self.id, BulletMLNode::Accel);
+        self.state = f(AccelBuilder {
+            state: self.state,
+            id,
+        })
+        .state;
+        self
+    }
+
+    /// Adds a `<wait>` of `frames` frames.
+    pub fn wait<E: Into<BuilderExpression>>(mut self, frames: E) -> Self {
+        let frames = self.state.expression(frames.into());
+        self.state.append(self.id, BulletMLNode::Wait(frames));
+        self
+    }
+
+    /// Adds a `<vanish>`.
+    pub fn vanish(mut self) -> Self {
+        self.state.append(self.id, BulletMLNode::Vanish);
+        self
+    }
+
+    /// Adds an anonymous nested `<action>`.
+    pub fn action<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(ActionBuilder) -> ActionBuilder,
+    {
+        self.state = ActionBuilder::build(self.state, self.id, None, f);
+        self
+    }
+
+    /// Adds an `<actionRef>` whose parameters are added by `f`.
+    pub fn action_ref<F>(mut self, label: &str, f: F) -> Self
+    where
+        F: FnOnce(RefBuilder) -> RefBuilder,
+    {
+        self.state = RefBuilder::build(self.state, self.id, label, ReferenceKind::Action, f);
+        self
+    }
+
+    /// Adds an application specific element whose attributes and parameters are added by `f`. It
+    /// is run by [AppRunner::do_custom](../trait.AppRunner.html#method.do_custom).
+    pub fn custom<F>(mut self, name: &str, f: F) -> Self
+    where
+        F: FnOnce(CustomBuilder) -> CustomBuilder,
+    {
+        let custom = f(CustomBuilder {
+            state: self.state,
+            attributes: Vec::new(),
+            params: Vec::new(),
+        });
+        self.state = custom.state;
+        self.state.append(
+            self.id,
+            BulletMLNode::Custom {
+                name: name.to_string(),
+                attributes: custom.attributes,
+                params: custom.params,
+            },
+        );
+        self
+    }
+}
+
+/// Builds the attributes and the parameters of a custom element.
+pub struct CustomBuilder {
+    state: BuildState,
+    attributes: Vec<(String, String)>,
+    params: Vec<BulletMLExpression>,
+}
+
+impl CustomBuilder {
+    /// Adds an attribute.
+    pub fn attribute(mut self, name: &str, value: &str) -> Self {
+        self.attributes.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds a parameter, evaluated when the element is run.
+    pub fn param<E: Into<BuilderExpression>>(mut self, param: E) -> Self {
+        let param = self.state.expression(param.into());
+        self.params.push(param);
+        self
+    }
+}
+
+/// Builds the content of a `<fire>`.
+pub struct FireBuilder {
+    state: BuildState,
+    id: NodeId,
+}
+
+impl FireBuilder {
+    fn build<F>(mut state: BuildState, parent: NodeId, label: Option<&str>, f: F) -> BuildState
+    where
+        F: FnOnce(FireBuilder) -> FireBuilder,
+    {
+        let id = state.append_definition(parent, label, ReferenceKind::Fire);
+        f(FireBuilder { state, id }).state
+    }
+
+    /// Sets the `<direction>` of the fired bullet.
+    pub fn direction<T, E>(mut self, dir_type: T, dir: E) -> Self
+    where
+        T: Into<Option<DirectionType>>,
+        E: Into<BuilderExpression>,
+    {
+        let dir = self.state.expression(dir.into());
+        self.state.append(
+            self.id,
+            BulletMLNode::Direction {
+                dir_type: dir_type.into(),
+                dir,
+            },
+        );
+        self
+    }
+
+    /// Sets the `<speed>` of the fired bullet.
+    pub fn speed<T, E>(mut self, spd_type: T, spd: E) -> Self
+    where
+        T: Into<Option<SpeedType>>,
+        E: Into<BuilderExpression>,
+    {
+        let spd = self.state.expression(spd.into());
+        self.state.append(
+            self.id,
+            BulletMLNode::Speed {
+                spd_type: spd_type.into(),
+                spd,
+            },
+        );
+        self
+    }
+
+    /// Sets the fired anonymous `<bullet>`.
+    pub fn bullet<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(BulletBuilder) -> BulletBuilder,
+    {
+        self.state = BulletBuilder::build(self.state, self.id, None, f);
+        self
+    }
+
+    /// Sets the fired bullet to a `<bulletRef>` whose parameters are added by `f`.
+    pub fn bullet_ref<F>(mut self, label: &str, f: F) -> Self
+    where
+        F: FnOnce(RefBuilder) -> RefBuilder,
+    {
+        self.state = RefBuilder::build(self.state, self.id, label, ReferenceKind::Bullet, f);
+        self
+    }
+}
+
+/// Builds the content of a `<bullet>`.
+pub struct BulletBuilder {
+    state: BuildState,
+    id: NodeId,
+}
+
+impl BulletBuilder {
+    fn build<F>(mut state: BuildState, parent: NodeId, label: Option<&str>, f: F) -> BuildState
+    where
+        F: FnOnce(BulletBuilder) -> BulletBuilder,
+    {
+        let id = state.append_definition(parent, label, ReferenceKind::Bullet);
+        f(BulletBuilder { state, id }).state
+    }
+
+    /// Sets the initial `<direction>` of the bullet.
+    pub fn direction<T, E>(mut self, dir_type: T, dir: E) -> Self
+    where
+        T: Into<Option<DirectionType>>,
+        E: Into<BuilderExpression>,
+    {
+        let dir = self.state.expression(dir.into());
+        self.state.append(
+            self.id,
+            BulletMLNode::Direction {
+                dir_type: dir_type.into(),
+                dir,
+            },
+        );
+        self
+    }
+
+    /// Sets the initial `<speed>` of the bullet.
+    pub fn speed<T, E>(mut self, spd_type: T, spd: E) -> Self
+    where
+        T: Into<Option<SpeedType>>,
+        E: Into<BuilderExpression>,
+    {
+        let spd = self.state.expression(spd.into());
+        self.state.append(
+            self.id,
+            BulletMLNode::Speed {
+                spd_type: spd_type.into(),
+                spd,
+            },
+        );
+        self
+    }
+
+    /// Adds an anonymous `<action>` run by the bullet.
+    pub fn action<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(ActionBuilder) -> ActionBuilder,
+    {
+        self.state = ActionBuilder::build(self.state, self.id, None, f);
+        self
+    }
+
+    /// Adds an `<actionRef>` run by the bullet, whose parameters are added by `f`.
+    pub fn action_ref<F>(mut self, label: &str, f: F) -> Self
+    where
+        F: FnOnce(RefBuilder) -> RefBuilder,
+    {
+        self.state = RefBuilder::build(self.state, self.id, label, ReferenceKind::Action, f);
+        self
+    }
+}
+
+/// Builds the content of an `<accel>`.
+pub struct AccelBuilder {
+    state: BuildState,
+    id: NodeId,
+}
+
+impl AccelBuilder {
+    /// Sets the `<horizontal>` acceleration.
+    pub fn horizontal<E: Into<BuilderExpression>>(mut self, h_type: HVType, h: E) -> Self {
+        let h = self.state.expression(h.into());
+        self.state
+            .append(self.id, BulletMLNode::Horizontal { h_type, h });
+        self
+    }
+
+    /// Sets the `<vertical>` acceleration.
+    pub fn vertical<E: Into<BuilderExpression>>(mut self, v_type: HVType, v: E) -> Self {
+        let v = self.state.expression(v.into());
+        self.state
+            .append(self.id, BulletMLNode::Vertical { v_type, v });
+        self
+    }
+
+    /// Sets the `<term>` of the acceleration, in frames.
+    pub fn term<E: Into<BuilderExpression>>(mut self, term: E) -> Self {
+        let term = self.state.expression(term.into());
+        self.state.append(self.id, BulletMLNode::Term(term));
+        self
+    }
+}
+
+/// Builds the parameters of a reference.
+pub struct RefBuilder {
+    state: BuildState,
+    id: NodeId,
+}
+
+impl RefBuilder {
+    fn build<F>(
+        mut state: BuildState,
+        parent: NodeId,
+        label: &str,
+        kind: ReferenceKind,
+        f: F,
+    ) -> BuildState
+    where
+        F: FnOnce(RefBuilder) -> RefBuilder,
+    {
+        let label = label.to_string();
+        let node = match kind {
+            ReferenceKind::Bullet => BulletMLNode::BulletRef(label),
+            ReferenceKind::Action => BulletMLNode::ActionRef(label),
+            ReferenceKind::Fire => BulletMLNode::FireRef(label),
+        };
+        let id = state.append(parent, node);
+        state.refs.push(id);
+        f(RefBuilder { state, id }).state
+    }
+
+    /// Adds a `<param>`, the first one is `$1` in the referenced element.
+    pub fn param<E: Into<BuilderExpression>>(mut self, param: E) -> Self {
+        let param = self.state.expression(param.into());
+        self.state.append(self.id, BulletMLNode::Param(param));
+        self
+    }
+}
+
+fn element_name(node: &BulletMLNode) -> &str {
+    BulletML::xml_parts(node).0
+}
+
+fn path(bml: &BulletML, id: NodeId) -> String {
+    NodePath::of(bml, id)
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| format!("{:?}", id))
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let bml = BulletMLBuilder::new()
+            .bml_type(BulletMLType::Vertical)
+            .top_action(|a| {
+                a.repeat("3 + $rank * 10", |a| {
+                    a.fire_ref("shot", |r| r.param(-10)).wait(5)
+                })
+                .action_ref("move", |r| r)
+            })
+            .top_action(|a| {
+                a.accel(|a| a.horizontal(HVType::Relative, 1.5).term(60))
+                    .vanish()
+            })
+            .action("move", |a| {
+                a.change_direction(DirectionType::Sequence, 2, 30)
+                    .change_speed(None, "$rand", 30)
+            })
+            .fire("shot", |f| {
+                f.direction(DirectionType::Aim, "$1 + $rand * 20")
+                    .speed(SpeedType::Absolute, 2)
+                    .bullet_ref("shell", |r| r)
+            })
+            .bullet("shell", |b| b.action(|a| a.wait(10).vanish()))
+            .build()
+            .unwrap();
+
+        let parsed = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml type="vertical">
+    <action label="top1">
+        <repeat>
+            <times>3 + $rank * 10</times>
+            <action>
+                <fireRef label="shot">
+                    <param>-10</param>
+                </fireRef>
+                <wait>5</wait>
+            </action>
+        </repeat>
+        <actionRef label="move" />
+    </action>
+    <action label="top2">
+        <accel>
+            <horizontal type="relative">1.5</horizontal>
+            <term>60</term>
+        </accel>
+        <vanish />
+    </action>
+    <action label="move">
+        <changeDirection>
+            <direction type="sequence">2</direction>
+            <term>30</term>
+        </changeDirection>
+        <changeSpeed>
+            <speed>$rand</speed>
+            <term>30</term>
+        </changeSpeed>
+    </action>
+    <fire label="shot">
+        <direction type="aim">$1 + $rand * 20</direction>
+        <speed type="absolute">2</speed>
+        <bulletRef label="shell" />
+    </fire>
+    <bullet label="shell">
+        <action>
+            <wait>10</wait>
+            <vanish />
+        </action>
+    </bullet>
+</bulletml>"##,
+            )
+            .unwrap();
+        assert_eq!(bml.to_xml_string(), parsed.to_xml_string());
+        assert_eq!(bml.action_refs.len(), 3);
+        assert_eq!(bml.fire_refs["shot"], parsed.fire_refs["shot"]);
+        assert!(bml.spans.is_empty());
+    }
+
+    #[test]
+    fn test_custom_element() {
+        let bml = BulletMLBuilder::new()
+            .top_action(|a| {
+                a.custom("shake", |c| {
+                    c.attribute("duration", "10").param("$rank * 2")
+                })
+                .custom("flash", |c| c)
+            })
+            .build()
+            .unwrap();
+        let parsed = BulletMLParser::new()
+            .custom_element("shake")
+            .custom_element("flash")
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top1">
+        <shake duration="10">
+            <param>$rank * 2</param>
+        </shake>
+        <flash />
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+        assert_eq!(bml.to_xml_string(), parsed.to_xml_string());
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let res = BulletMLBuilder::new()
+            .action("move", |a| a.wait(1))
+            .top_action(|a| a.wait(1))
+            .action("move", |a| a.wait(2))
+            .build();
+        assert_matches!(
+            &res,
+            Err(BuildError::DuplicateLabel { label, element, .. })
+                if label == "move" && element == "action"
+        );
+    }
+
+    #[test]
+    fn test_missing_element() {
+        let res = BulletMLBuilder::new()
+            .top_action(|a| a.fire(|f| f.direction(None, 0)))
+            .build();
+        assert_eq!(
+            format!("{}", res.unwrap_err()),
+            "Missing element bullet | bulletRef in element fire at action(top1)/0"
+        );
+    }
+
+    #[test]
+    fn test_misplaced_element() {
+        let res = BulletMLBuilder::new()
+            .fire("shot", |f| f.bullet(|b| b).speed(None, 1))
+            .build();
+        assert_eq!(
+            format!("{}", res.unwrap_err()),
+            "Misplaced element speed in element fire at fire(shot)/1"
+        );
+    }
+
+    #[test]
+    fn test_unresolved_reference() {
+        let res = BulletMLBuilder::new()
+            .top_action(|a| a.wait(1).fire(|f| f.bullet_ref("missing", |r| r)))
+            .build();
+        assert_matches!(
+            &res,
+            Err(BuildError::UnresolvedReference { label, kind: ReferenceKind::Bullet, path, .. })
+                if label == "missing" && path == "action(top1)/1/0"
+        );
+    }
+
+    #[test]
+    fn test_parameter_count() {
+        let res = BulletMLBuilder::new()
+            .top_action(|a| a.action_ref("move", |r| r.param(1)))
+            .action("move", |a| a.change_speed(None, "$1 + $2", 10))
+            .build();
+        assert_matches!(
+            &res,
+            Err(BuildError::ParameterCount { label, expected: 2, found: 1, .. }) if label == "move"
+        );
+    }
+
+    #[test]
+    fn test_unguarded_recursion() {
+        let builder = || {
+            BulletMLBuilder::new()
+                .top_action(|a| a.action_ref("loop", |r| r))
+                .action("loop", |a| {
+                    a.fire(|f| f.bullet(|b| b)).action_ref("loop", |r| r)
+                })
+        };
+        assert_matches!(
+            builder().build(),
+            Err(BuildError::UnguardedRecursion { ref label, .. }) if label == "loop"
+        );
+        assert!(builder().allow_unguarded_recursion(true).build().is_ok());
+    }
+
+    #[test]
+    fn test_expression_error() {
+        let res = BulletMLBuilder::new()
+            .top_action(|a| a.wait(" 1 + ").wait("2 *"))
+            .build();
+        assert_matches!(
+            &res,
+            Err(BuildError::Expression { expression, .. }) if expression == "1 +"
+        );
+    }
+}
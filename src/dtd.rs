@@ -0,0 +1,145 @@
+/// A group of alternative elements in a content model, with its cardinality.
+struct Particle {
+    names: &'static [&'static str],
+    min: usize,
+    max: Option<usize>,
+}
+
+const fn one(names: &'static [&'static str]) -> Particle {
+    Particle {
+        names,
+        min: 1,
+        max: Some(1),
+    }
+}
+
+const fn optional(names: &'static [&'static str]) -> Particle {
+    Particle {
+        names,
+        min: 0,
+        max: Some(1),
+    }
+}
+
+const fn any(names: &'static [&'static str]) -> Particle {
+    Particle {
+        names,
+        min: 0,
+        max: None,
+    }
+}
+
+const BULLETML: &[Particle] = &[any(&["bullet", "action", "fire", "import"])];
+const BULLET: &[Particle] = &[
+    optional(&["direction"]),
+    optional(&["speed"]),
+    any(&["action", "actionRef"]),
+];
+const ACTION: &[Particle] = &[any(&[
+    "changeDirection",
+    "accel",
+    "vanish",
+    "changeSpeed",
+    "repeat",
+    "wait",
+    "fire",
+    "fireRef",
+    "action",
+    "actionRef",
+])];
+const FIRE: &[Particle] = &[
+    optional(&["direction"]),
+    optional(&["speed"]),
+    one(&["bullet", "bulletRef"]),
+];
+const CHANGE_DIRECTION: &[Particle] = &[one(&["direction"]), one(&["term"])];
+const CHANGE_SPEED: &[Particle] = &[one(&["speed"]), one(&["term"])];
+const ACCEL: &[Particle] = &[
+    optional(&["horizontal"]),
+    optional(&["vertical"]),
+    one(&["term"]),
+];
+const REPEAT: &[Particle] = &[one(&["times"]), one(&["action", "actionRef"])];
+const REF: &[Particle] = &[any(&["param"])];
+const TEXT: &[Particle] = &[];
+
+/// Returns the content model of `element` as declared in bulletml.dtd, or `None` if the element
+/// is unknown.
+fn content_model(element: &str) -> Option<&'static [Particle]> {
+    match element {
+        "bulletml" => Some(BULLETML),
+        "bullet" => Some(BULLET),
+        "action" => Some(ACTION),
+        "fire" => Some(FIRE),
+        "changeDirection" => Some(CHANGE_DIRECTION),
+        "changeSpeed" => Some(CHANGE_SPEED),
+        "accel" => Some(ACCEL),
+        "repeat" => Some(REPEAT),
+        "bulletRef" | "actionRef" | "fireRef" => Some(REF),
+        "wait" | "vanish" | "direction" | "speed" | "horizontal" | "vertical" | "term"
+        | "times" | "param" | "import" => Some(TEXT),
+        _ => None,
+    }
+}
+
+/// Violation of a content model found by [check_content](fn.check_content.html).
+pub(crate) enum ContentError<'a, P> {
+    /// One of the `expected` elements is required.
+    Missing { expected: String },
+    /// `child` is not allowed at that place.
+    Misplaced { child: &'a str, pos: P },
+}
+
+/// Checks the child elements of `element` against its content model.
+///
+/// The content models of bulletml.dtd are deterministic, so each particle can greedily take as
+/// many children as it accepts. Unknown elements and children which are not allowed at all in
+/// `element` are not checked, the parser rejects them.
+///
+/// `P` locates each child, e.g. its position in the document.
+pub(crate) fn check_content<'a, P, I>(element: &str, children: I) -> Result<(), ContentError<'a, P>>
+where
+    I: IntoIterator<Item = (&'a str, P)>,
+{
+    let model = if let Some(model) = content_model(element) {
+        model
+    } else {
+        return Ok(());
+    };
+    let mut children = children
+        .into_iter()
+        .filter(|(name, _)| model.iter().any(|particle| particle.names.contains(name)))
+        .peekable();
+    for (index, particle) in model.iter().enumerate() {
+        let mut count = 0;
+        while particle.max.map(|max| count < max).unwrap_or(true) {
+            match children.peek() {
+                Some((name, _)) if particle.names.contains(name) => {
+                    children.next();
+                    count += 1;
+                }
+                _ => break,
+            }
+        }
+        if count < particle.min {
+            // A child which would be accepted later means the required one is missing, otherwise
+            // that child is the one out of place.
+            return Err(match children.next() {
+                Some((child, pos))
+                    if !model[index + 1..]
+                        .iter()
+                        .any(|particle| particle.names.contains(&child)) =>
+                {
+                    ContentError::Misplaced { child, pos }
+                }
+                _ => ContentError::Missing {
+                    expected: particle.names.join(" | "),
+                },
+            });
+        }
+    }
+    if let Some((child, pos)) = children.next() {
+        return Err(ContentError::Misplaced { child, pos });
+    }
+    Ok(())
+}
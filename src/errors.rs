@@ -1,3 +1,4 @@
+use indextree::NodeId;
 use roxmltree::TextPos;
 #[cfg(feature = "backtrace")]
 use std::backtrace::Backtrace;
@@ -24,6 +25,40 @@ pub enum ParseError {
         backtrace: Backtrace,
     },
 
+    #[error("Unknown encoding {encoding}")]
+    UnknownEncoding {
+        encoding: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Invalid {encoding} data at byte {offset}")]
+    Decoding {
+        encoding: String,
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Error in document {src} imported at position {pos}")]
+    Import {
+        src: String,
+        pos: ParseErrorPos,
+        source: Box<ParseError>,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Document {src} imports itself at position {pos}")]
+    ImportCycle {
+        src: String,
+        pos: ParseErrorPos,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
     #[error("Unexpected element {element} at position {pos}")]
     UnexpectedElement {
         element: String,
@@ -41,6 +76,24 @@ pub enum ParseError {
         #[new(value = "Backtrace::capture()")]
         backtrace: Backtrace,
     },
+    #[error("Missing element {expected} in element {element} at position {pos}")]
+    MissingElement {
+        expected: String,
+        element: String,
+        pos: ParseErrorPos,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Misplaced element {child} in element {element} at position {pos}")]
+    MisplacedElement {
+        child: String,
+        element: String,
+        pos: ParseErrorPos,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
     #[error("Unexpected node of type {node_type} at position {pos}")]
     UnexpectedNodeType {
         node_type: String,
@@ -83,9 +136,51 @@ pub enum ParseError {
         backtrace: Backtrace,
     },
 
-    #[error("Expression error at position {pos}")]
+    #[error("Unresolved label {label} in element {kind} at position {pos}")]
+    UnresolvedReference {
+        label: String,
+        kind: ReferenceKind,
+        pos: ParseErrorPos,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Label {label} in element {kind} expects {expected} parameters but {found} are passed at position {pos}")]
+    ParameterCount {
+        label: String,
+        kind: ReferenceKind,
+        expected: usize,
+        found: usize,
+        pos: ParseErrorPos,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Label {label} in element {kind} recurses without waiting at position {pos}")]
+    UnguardedRecursion {
+        label: String,
+        kind: ReferenceKind,
+        pos: ParseErrorPos,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Element {element} nested too deeply at position {pos}")]
+    NestingTooDeep {
+        element: String,
+        pos: ParseErrorPos,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Expression error in {expression:?} at position {pos}")]
     Expression {
-        source: fasteval::Error,
+        source: ExpressionError,
+        expression: String,
         pos: ParseErrorPos,
         #[cfg(feature = "backtrace")]
         #[new(value = "Backtrace::capture()")]
@@ -93,7 +188,364 @@ pub enum ParseError {
     },
 }
 
+impl ParseError {
+    /// Returns the position of the error in the document, if known.
+    pub fn pos(&self) -> Option<ParseErrorPos> {
+        match self {
+            ParseError::Io { .. }
+            | ParseError::UnknownEncoding { .. }
+            | ParseError::Decoding { .. } => None,
+            ParseError::Xml { source, .. } => Some(source.pos().into()),
+            ParseError::Import { pos, .. }
+            | ParseError::ImportCycle { pos, .. }
+            | ParseError::UnexpectedElement { pos, .. }
+            | ParseError::MissingAttribute { pos, .. }
+            | ParseError::MissingElement { pos, .. }
+            | ParseError::MisplacedElement { pos, .. }
+            | ParseError::UnexpectedNodeType { pos, .. }
+            | ParseError::UnrecognizedBmlType { pos, .. }
+            | ParseError::UnrecognizedDirectionType { pos, .. }
+            | ParseError::UnrecognizedSpeedType { pos, .. }
+            | ParseError::UnrecognizedAccelDirType { pos, .. }
+            | ParseError::UnresolvedReference { pos, .. }
+            | ParseError::ParameterCount { pos, .. }
+            | ParseError::UnguardedRecursion { pos, .. }
+            | ParseError::NestingTooDeep { pos, .. }
+            | ParseError::Expression { pos, .. } => Some(*pos),
+        }
+    }
+}
+
+/// Severity of a [Diagnostic](struct.Diagnostic.html).
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Severity {
+    /// The document is wrong and the related element was skipped or replaced by a default.
+    Error,
+    /// The document is suspicious but usable as is.
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// An issue found in a BulletML document by
+/// [parse_with_diagnostics](../parse/struct.BulletMLParser.html#method.parse_with_diagnostics).
+#[derive(Debug, new)]
+pub struct Diagnostic {
+    severity: Severity,
+    error: ParseError,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn error(&self) -> &ParseError {
+        &self.error
+    }
+
+    pub fn into_error(self) -> ParseError {
+        self.error
+    }
+
+    /// Returns the position of the issue in the document, if known.
+    pub fn pos(&self) -> Option<ParseErrorPos> {
+        self.error.pos()
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: {}", self.severity, self.error))
+    }
+}
+
+/// Error raised when a runner cannot be moved to a reloaded BulletML document.
+#[derive(Error, Debug, new)]
+pub enum MigrationError {
+    #[error("Node {path} has no counterpart in the new document")]
+    MissingNode {
+        path: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+}
+
+/// Error raised when a runner cannot be started or run. The errors raised while running refer
+/// to the node which was being run.
+#[derive(Error, Debug, new)]
+pub enum RunError {
+    #[error("Unknown action {label}")]
+    UnknownAction {
+        label: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Action {label} expects {expected} parameters but {found} are passed")]
+    ParameterCount {
+        label: String,
+        expected: usize,
+        found: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Unresolved label {label} in element {kind} at node {node}")]
+    UnresolvedReference {
+        label: String,
+        kind: ReferenceKind,
+        node: NodeId,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Missing parameter ${index} at node {node}")]
+    MissingParameter {
+        index: usize,
+        node: NodeId,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Missing action in repeat at node {node}")]
+    MissingAction {
+        node: NodeId,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Missing bullet in fire at node {node}")]
+    MissingBullet {
+        node: NodeId,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Missing times in repeat at node {node}")]
+    MissingTimes {
+        node: NodeId,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Repeat not entered by the runner at node {node}")]
+    UnbalancedRepeat {
+        node: NodeId,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Node {node} has no parent")]
+    DetachedNode {
+        node: NodeId,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+}
+
+impl RunError {
+    /// Returns the node which was being run, if the error was raised while running.
+    pub fn node(&self) -> Option<NodeId> {
+        match self {
+            RunError::UnknownAction { .. } | RunError::ParameterCount { .. } => None,
+            RunError::UnresolvedReference { node, .. }
+            | RunError::MissingParameter { node, .. }
+            | RunError::MissingAction { node, .. }
+            | RunError::MissingTimes { node, .. }
+            | RunError::MissingBullet { node, .. }
+            | RunError::UnbalancedRepeat { node, .. }
+            | RunError::DetachedNode { node, .. } => Some(*node),
+        }
+    }
+}
+
+/// All kinds of error that can happen when building a BulletML document with
+/// [BulletMLBuilder](../build/struct.BulletMLBuilder.html).
+///
+/// Elements are located by their path: the nearest labelled ancestor, or `bulletml`, followed by
+/// the child indices down to the element, e.g. `action(top1)/0/1`.
+#[derive(Error, Debug, new)]
+pub enum BuildError {
+    #[error("Label {label} is defined more than once in element {element}")]
+    DuplicateLabel {
+        label: String,
+        element: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Missing element {expected} in element {element} at {path}")]
+    MissingElement {
+        expected: String,
+        element: String,
+        path: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Misplaced element {child} in element {element} at {path}")]
+    MisplacedElement {
+        child: String,
+        element: String,
+        path: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Unresolved label {label} in element {kind} at {path}")]
+    UnresolvedReference {
+        label: String,
+        kind: ReferenceKind,
+        path: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Label {label} in element {kind} expects {expected} parameters but {found} are passed at {path}")]
+    ParameterCount {
+        label: String,
+        kind: ReferenceKind,
+        expected: usize,
+        found: usize,
+        path: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Label {label} in element {kind} recurses without waiting at {path}")]
+    UnguardedRecursion {
+        label: String,
+        kind: ReferenceKind,
+        path: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+
+    #[error("Expression error in {expression:?}")]
+    Expression {
+        source: ExpressionError,
+        expression: String,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+}
+
+/// All kinds of error that can happen when compiling an expression. The offset is the byte
+/// offset of the error in the expression text.
+#[derive(Error, Debug, new)]
+pub enum ExpressionError {
+    #[error("Unexpected end of expression at offset {offset}")]
+    UnexpectedEnd {
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Unexpected character {character:?} at offset {offset}")]
+    UnexpectedCharacter {
+        character: char,
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Invalid parameter {name} at offset {offset}")]
+    InvalidParameter {
+        name: String,
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Undefined variable {name} at offset {offset}")]
+    UndefinedVariable {
+        name: String,
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Undefined function {name} at offset {offset}")]
+    UndefinedFunction {
+        name: String,
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Function {name} does not accept {found} arguments at offset {offset}")]
+    ArgumentCount {
+        name: String,
+        found: usize,
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+    #[error("Expression nested too deeply at offset {offset}")]
+    NestingTooDeep {
+        offset: usize,
+        #[cfg(feature = "backtrace")]
+        #[new(value = "Backtrace::capture()")]
+        backtrace: Backtrace,
+    },
+}
+
+impl ExpressionError {
+    /// Returns the byte offset of the error in the expression text.
+    pub fn offset(&self) -> usize {
+        match self {
+            ExpressionError::UnexpectedEnd { offset, .. }
+            | ExpressionError::UnexpectedCharacter { offset, .. }
+            | ExpressionError::InvalidParameter { offset, .. }
+            | ExpressionError::UndefinedVariable { offset, .. }
+            | ExpressionError::UndefinedFunction { offset, .. }
+            | ExpressionError::ArgumentCount { offset, .. }
+            | ExpressionError::NestingTooDeep { offset, .. } => *offset,
+        }
+    }
+}
+
+/// Kind of a reference element, i.e. `bulletRef`, `actionRef` or `fireRef`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ReferenceKind {
+    Bullet,
+    Action,
+    Fire,
+}
+
+impl Display for ReferenceKind {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ReferenceKind::Bullet => "bulletRef",
+            ReferenceKind::Action => "actionRef",
+            ReferenceKind::Fire => "fireRef",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct ParseErrorPos {
     row: u32,
     col: u32,
@@ -107,6 +559,23 @@ impl ParseErrorPos {
     pub fn col(&self) -> u32 {
         self.col
     }
+
+    /// Returns the position reached after reading `text` from this position.
+    pub(crate) fn advance(self, text: &str) -> Self {
+        text.chars().fold(self, |pos, c| {
+            if c == '\n' {
+                ParseErrorPos {
+                    row: pos.row.saturating_add(1),
+                    col: 1,
+                }
+            } else {
+                ParseErrorPos {
+                    row: pos.row,
+                    col: pos.col.saturating_add(1),
+                }
+            }
+        })
+    }
 }
 
 impl Display for ParseErrorPos {
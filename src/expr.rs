@@ -0,0 +1,892 @@
+use crate::errors::ExpressionError;
+use std::collections::HashSet;
+
+/// Expression compiled to a postfix sequence of operations, evaluated on a stack.
+///
+/// Unlike a slab based representation, a compiled expression owns all its operations so that
+/// there is no capacity to configure and expressions can be created at any time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledExpression {
+    ops: Box<[Op]>,
+    depth: usize,
+}
+
+/// Depth up to which expressions are evaluated on a stack which is not allocated.
+const INLINE_STACK: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Const(f64),
+    /// `$N`, with `N` starting from 1.
+    Parameter(usize),
+    Rank,
+    Rand,
+    /// `$loop.N.index`, with `N` starting from 0 for the innermost loop.
+    LoopIndex(usize),
+    Variable(Box<str>),
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    /// Built-in function with its number of arguments.
+    Builtin(Builtin, usize),
+    /// Application function with its number of arguments.
+    Function(Box<str>, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Builtin {
+    Int,
+    Ceil,
+    Floor,
+    Abs,
+    Sign,
+    Log,
+    Round,
+    Min,
+    Max,
+    E,
+    Pi,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Sinh,
+    Cosh,
+    Tanh,
+    Asinh,
+    Acosh,
+    Atanh,
+}
+
+impl Builtin {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "int" => Builtin::Int,
+            "ceil" => Builtin::Ceil,
+            "floor" => Builtin::Floor,
+            "abs" => Builtin::Abs,
+            "sign" => Builtin::Sign,
+            "log" => Builtin::Log,
+            "round" => Builtin::Round,
+            "min" => Builtin::Min,
+            "max" => Builtin::Max,
+            "e" => Builtin::E,
+            "pi" => Builtin::Pi,
+            "sin" => Builtin::Sin,
+            "cos" => Builtin::Cos,
+            "tan" => Builtin::Tan,
+            "asin" => Builtin::Asin,
+            "acos" => Builtin::Acos,
+            "atan" => Builtin::Atan,
+            "sinh" => Builtin::Sinh,
+            "cosh" => Builtin::Cosh,
+            "tanh" => Builtin::Tanh,
+            "asinh" => Builtin::Asinh,
+            "acosh" => Builtin::Acosh,
+            "atanh" => Builtin::Atanh,
+            _ => return None,
+        })
+    }
+
+    fn accepts(self, count: usize) -> bool {
+        match self {
+            Builtin::E | Builtin::Pi => count == 0,
+            Builtin::Log | Builtin::Round => count == 1 || count == 2,
+            Builtin::Min | Builtin::Max => count >= 1,
+            _ => count == 1,
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        match (self, args) {
+            (Builtin::Int, [x]) => x.trunc(),
+            (Builtin::Ceil, [x]) => x.ceil(),
+            (Builtin::Floor, [x]) => x.floor(),
+            (Builtin::Abs, [x]) => x.abs(),
+            (Builtin::Sign, [x]) => {
+                if *x > 0. {
+                    1.
+                } else if *x < 0. {
+                    -1.
+                } else {
+                    0.
+                }
+            }
+            (Builtin::Log, [x]) => x.log10(),
+            (Builtin::Log, [base, x]) => x.log(*base),
+            (Builtin::Round, [x]) => x.round(),
+            (Builtin::Round, [modulus, x]) => (x / modulus).round() * modulus,
+            (Builtin::Min, args) => args.iter().copied().fold(f64::INFINITY, f64::min),
+            (Builtin::Max, args) => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            (Builtin::E, []) => std::f64::consts::E,
+            (Builtin::Pi, []) => std::f64::consts::PI,
+            (Builtin::Sin, [x]) => x.sin(),
+            (Builtin::Cos, [x]) => x.cos(),
+            (Builtin::Tan, [x]) => x.tan(),
+            (Builtin::Asin, [x]) => x.asin(),
+            (Builtin::Acos, [x]) => x.acos(),
+            (Builtin::Atan, [x]) => x.atan(),
+            (Builtin::Sinh, [x]) => x.sinh(),
+            (Builtin::Cosh, [x]) => x.cosh(),
+            (Builtin::Tanh, [x]) => x.tanh(),
+            (Builtin::Asinh, [x]) => x.asinh(),
+            (Builtin::Acosh, [x]) => x.acosh(),
+            (Builtin::Atanh, [x]) => x.atanh(),
+            _ => unreachable!("arguments checked at compile time"),
+        }
+    }
+}
+
+/// Values an expression depends on, provided when it is evaluated.
+pub(crate) trait Context {
+    /// Gets the value of `$index`, `index` starting from 1.
+    fn parameter(&mut self, index: usize) -> f64;
+    fn rank(&mut self) -> f64;
+    fn rand(&mut self) -> f64;
+    /// Gets the iteration of the loop `depth` levels above the innermost one.
+    fn loop_index(&mut self, depth: usize) -> f64;
+    fn variable(&mut self, name: &str) -> f64;
+    fn function(&mut self, name: &str, args: &[f64]) -> f64;
+}
+
+impl CompiledExpression {
+    /// Evaluates the expression with the values given by `context`.
+    pub(crate) fn eval(&self, context: &mut dyn Context) -> f64 {
+        if let Some(value) = self.constant() {
+            return value;
+        }
+        // Evaluation runs every frame, the stack is only allocated for unusually deep expressions,
+        // e.g. functions with many arguments.
+        if self.depth <= INLINE_STACK {
+            self.eval_on(&mut [0.; INLINE_STACK], context)
+        } else {
+            self.eval_on(&mut vec![0.; self.depth], context)
+        }
+    }
+
+    /// Evaluates the operations on `stack`, which must be at least as long as the depth.
+    fn eval_on(&self, stack: &mut [f64], context: &mut dyn Context) -> f64 {
+        let mut len = 0;
+        for op in self.ops.iter() {
+            let value = match op {
+                Op::Const(value) => *value,
+                Op::Parameter(index) => context.parameter(*index),
+                Op::Rank => context.rank(),
+                Op::Rand => context.rand(),
+                Op::LoopIndex(depth) => context.loop_index(*depth),
+                Op::Variable(name) => context.variable(name),
+                Op::Function(name, count) => {
+                    len -= count;
+                    context.function(name, &stack[len..len + count])
+                }
+                op => {
+                    len -= op.pops();
+                    op.apply(&stack[len..len + op.pops()])
+                }
+            };
+            stack[len] = value;
+            len += 1;
+        }
+        stack[len - 1]
+    }
+
+    /// Gets the value of the expression if it does not depend on anything.
+    pub(crate) fn constant(&self) -> Option<f64> {
+        match *self.ops {
+            [Op::Const(value)] => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Replaces `$rank` by `rank` and folds the operations which become constant.
+    pub(crate) fn specialize_for_rank(&self, rank: f64) -> CompiledExpression {
+        let mut ops = Ops::default();
+        for op in self.ops.iter() {
+            ops.push(match op {
+                Op::Rank => Op::Const(rank),
+                op => op.clone(),
+            });
+        }
+        ops.finish()
+    }
+
+    /// Gets the highest parameter (`$1`, `$2`, ...) used by the expression.
+    pub(crate) fn max_parameter(&self) -> usize {
+        self.ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::Parameter(index) => Some(*index),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl Op {
+    /// Gets the number of values the operation takes from the stack.
+    fn pops(&self) -> usize {
+        match self {
+            Op::Const(..)
+            | Op::Parameter(..)
+            | Op::Rank
+            | Op::Rand
+            | Op::LoopIndex(..)
+            | Op::Variable(..) => 0,
+            Op::Neg => 1,
+            Op::Builtin(_, count) | Op::Function(_, count) => *count,
+            _ => 2,
+        }
+    }
+
+    /// Tells whether the result of the operation only depends on its arguments.
+    fn is_pure(&self) -> bool {
+        !matches!(
+            self,
+            Op::Const(..)
+                | Op::Parameter(..)
+                | Op::Rank
+                | Op::Rand
+                | Op::LoopIndex(..)
+                | Op::Variable(..)
+                | Op::Function(..)
+        )
+    }
+
+    /// Applies a pure operation to its arguments.
+    fn apply(&self, args: &[f64]) -> f64 {
+        match (self, args) {
+            (Op::Neg, [x]) => -x,
+            (Op::Add, [left, right]) => left + right,
+            (Op::Sub, [left, right]) => left - right,
+            (Op::Mul, [left, right]) => left * right,
+            (Op::Div, [left, right]) => left / right,
+            (Op::Rem, [left, right]) => left % right,
+            (Op::Pow, [left, right]) => left.powf(*right),
+            (Op::Lt, [left, right]) => truth(left < right),
+            (Op::Le, [left, right]) => truth(left <= right),
+            (Op::Gt, [left, right]) => truth(left > right),
+            (Op::Ge, [left, right]) => truth(left >= right),
+            (Op::Eq, [left, right]) => truth(left == right),
+            (Op::Ne, [left, right]) => truth(left != right),
+            (Op::Builtin(builtin, _), args) => builtin.apply(args),
+            _ => unreachable!("not a pure operation"),
+        }
+    }
+}
+
+/// Comparisons evaluate to 1 when true and 0 otherwise.
+fn truth(value: bool) -> f64 {
+    if value {
+        1.
+    } else {
+        0.
+    }
+}
+
+/// Sequence of operations being built.
+#[derive(Default)]
+struct Ops {
+    ops: Vec<Op>,
+    /// Size of the evaluation stack after the operations pushed so far.
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Ops {
+    /// Appends `op`, or its result when all its arguments are constants.
+    fn push(&mut self, op: Op) {
+        let pops = op.pops();
+        self.depth = self.depth - pops + 1;
+        self.max_depth = self.max_depth.max(self.depth);
+        // Each constant pushes a single value, so the last operations are the arguments.
+        let args = self.ops.len() - pops;
+        if op.is_pure()
+            && self.ops[args..]
+                .iter()
+                .all(|op| matches!(op, Op::Const(..)))
+        {
+            let values = self.ops[args..]
+                .iter()
+                .map(|op| match op {
+                    Op::Const(value) => *value,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            self.ops.truncate(args);
+            self.ops.push(Op::Const(op.apply(&values)));
+        } else {
+            self.ops.push(op);
+        }
+    }
+
+    fn finish(self) -> CompiledExpression {
+        CompiledExpression {
+            ops: self.ops.into_boxed_slice(),
+            depth: self.max_depth,
+        }
+    }
+}
+
+/// Application variables and functions accepted in expressions.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AppNames {
+    pub variables: HashSet<String>,
+    pub functions: HashSet<String>,
+    /// Accepts any name, for expressions which were already checked.
+    pub any: bool,
+}
+
+impl AppNames {
+    #[cfg(feature = "serde")]
+    pub fn any() -> Self {
+        AppNames {
+            any: true,
+            ..AppNames::default()
+        }
+    }
+}
+
+/// Compiles `text` with the grammar:
+///
+/// ```text
+/// expr    := sum (("<" | "<=" | ">" | ">=" | "==" | "!=") sum)*
+/// sum     := term (("+" | "-") term)*
+/// term    := unary (("*" | "/" | "%") unary)*
+/// unary   := ("-" | "+") unary | power
+/// power   := primary ("^" unary)?
+/// primary := number | "(" expr ")" | "$" variable | name "(" (expr ("," expr)*)? ")"
+/// ```
+pub(crate) fn compile(text: &str, names: &AppNames) -> Result<CompiledExpression, ExpressionError> {
+    let mut compiler = Compiler {
+        text,
+        pos: 0,
+        names,
+        ops: Ops::default(),
+        nesting: 0,
+    };
+    compiler.expr()?;
+    compiler.skip_whitespaces();
+    if let Some(character) = compiler.peek() {
+        return Err(ExpressionError::new_unexpected_character(
+            character,
+            compiler.pos,
+        ));
+    }
+    Ok(compiler.ops.finish())
+}
+
+/// Maximum number of nested parentheses, signs, powers and calls in an expression.
+const MAX_NESTING: usize = 128;
+
+struct Compiler<'a> {
+    text: &'a str,
+    pos: usize,
+    names: &'a AppNames,
+    ops: Ops,
+    /// Number of nested parentheses, signs, powers and calls being compiled.
+    nesting: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn expr(&mut self) -> Result<(), ExpressionError> {
+        self.sum()?;
+        loop {
+            self.skip_whitespaces();
+            let rest = &self.text[self.pos..];
+            let (op, len) = if rest.starts_with("<=") {
+                (Op::Le, 2)
+            } else if rest.starts_with(">=") {
+                (Op::Ge, 2)
+            } else if rest.starts_with("==") {
+                (Op::Eq, 2)
+            } else if rest.starts_with("!=") {
+                (Op::Ne, 2)
+            } else if rest.starts_with('<') {
+                (Op::Lt, 1)
+            } else if rest.starts_with('>') {
+                (Op::Gt, 1)
+            } else {
+                return Ok(());
+            };
+            self.pos += len;
+            self.sum()?;
+            self.ops.push(op);
+        }
+    }
+
+    fn sum(&mut self) -> Result<(), ExpressionError> {
+        self.term()?;
+        loop {
+            let op = match self.next_operator() {
+                Some('+') => Op::Add,
+                Some('-') => Op::Sub,
+                _ => return Ok(()),
+            };
+            self.pos += 1;
+            self.term()?;
+            self.ops.push(op);
+        }
+    }
+
+    fn term(&mut self) -> Result<(), ExpressionError> {
+        self.unary()?;
+        loop {
+            let op = match self.next_operator() {
+                Some('*') => Op::Mul,
+                Some('/') => Op::Div,
+                Some('%') => Op::Rem,
+                _ => return Ok(()),
+            };
+            self.pos += 1;
+            self.unary()?;
+            self.ops.push(op);
+        }
+    }
+
+    fn unary(&mut self) -> Result<(), ExpressionError> {
+        match self.next_operator() {
+            Some('-') => {
+                self.pos += 1;
+                self.nested(Self::unary)?;
+                self.ops.push(Op::Neg);
+                Ok(())
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.nested(Self::unary)
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<(), ExpressionError> {
+        self.primary()?;
+        if self.next_operator() == Some('^') {
+            self.pos += 1;
+            self.nested(Self::unary)?;
+            self.ops.push(Op::Pow);
+        }
+        Ok(())
+    }
+
+    fn primary(&mut self) -> Result<(), ExpressionError> {
+        self.skip_whitespaces();
+        let start = self.pos;
+        match self.peek() {
+            None => Err(ExpressionError::new_unexpected_end(start)),
+            Some('(') => {
+                self.pos += 1;
+                self.nested(Self::expr)?;
+                self.expect(')')
+            }
+            Some('$') => {
+                self.pos += 1;
+                self.variable(start)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if is_name_start(c) => {
+                let name = self.name();
+                self.skip_whitespaces();
+                if self.peek() != Some('(') {
+                    return Err(ExpressionError::new_undefined_variable(
+                        name.to_string(),
+                        start,
+                    ));
+                }
+                self.pos += 1;
+                self.call(name, start)
+            }
+            Some(character) => Err(ExpressionError::new_unexpected_character(character, start)),
+        }
+    }
+
+    fn number(&mut self) -> Result<(), ExpressionError> {
+        let start = self.pos;
+        self.digits();
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            self.digits();
+        }
+        if let Some('e') | Some('E') = self.peek() {
+            let rest = &self.text[self.pos + 1..];
+            let sign = rest.starts_with('+') || rest.starts_with('-');
+            if rest[sign as usize..].starts_with(|c: char| c.is_ascii_digit()) {
+                self.pos += 1 + sign as usize;
+                self.digits();
+            }
+        }
+        let value = self.text[start..self.pos]
+            .parse()
+            .map_err(|_| ExpressionError::new_unexpected_character('.', start))?;
+        self.ops.push(Op::Const(value));
+        Ok(())
+    }
+
+    fn variable(&mut self, start: usize) -> Result<(), ExpressionError> {
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                let digits = self.digits();
+                match digits.parse() {
+                    Ok(index) if index > 0 => {
+                        self.ops.push(Op::Parameter(index));
+                        Ok(())
+                    }
+                    _ => Err(ExpressionError::new_invalid_parameter(
+                        format!("${}", digits),
+                        start,
+                    )),
+                }
+            }
+            Some(c) if is_name_start(c) => {
+                let op = match self.name() {
+                    "rank" => Op::Rank,
+                    "rand" => Op::Rand,
+                    "loop" => Op::LoopIndex(self.loop_depth()?),
+                    name if self.names.any || self.names.variables.contains(name) => {
+                        Op::Variable(name.into())
+                    }
+                    name => {
+                        return Err(ExpressionError::new_undefined_variable(
+                            format!("${}", name),
+                            start,
+                        ))
+                    }
+                };
+                self.ops.push(op);
+                Ok(())
+            }
+            Some(character) => Err(ExpressionError::new_unexpected_character(
+                character, self.pos,
+            )),
+            None => Err(ExpressionError::new_unexpected_end(self.pos)),
+        }
+    }
+
+    /// Parses the end of `$loop.index` or `$loop.N.index`.
+    fn loop_depth(&mut self) -> Result<usize, ExpressionError> {
+        self.expect_exactly('.')?;
+        let depth = if matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            let start = self.pos;
+            let digits = self.digits();
+            let depth = digits.parse().map_err(|_| {
+                ExpressionError::new_invalid_parameter(format!("$loop.{}", digits), start)
+            })?;
+            self.expect_exactly('.')?;
+            depth
+        } else {
+            0
+        };
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if is_name_start(c) && self.name() == "index" => Ok(depth),
+            Some(character) => Err(ExpressionError::new_unexpected_character(character, start)),
+            None => Err(ExpressionError::new_unexpected_end(start)),
+        }
+    }
+
+    fn call(&mut self, name: &str, start: usize) -> Result<(), ExpressionError> {
+        let mut count = 0;
+        self.skip_whitespaces();
+        if self.peek() == Some(')') {
+            self.pos += 1;
+        } else {
+            loop {
+                self.nested(Self::expr)?;
+                count += 1;
+                self.skip_whitespaces();
+                match self.peek() {
+                    Some(',') => self.pos += 1,
+                    Some(')') => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some(character) => {
+                        return Err(ExpressionError::new_unexpected_character(
+                            character, self.pos,
+                        ))
+                    }
+                    None => return Err(ExpressionError::new_unexpected_end(self.pos)),
+                }
+            }
+        }
+        let op = if let Some(builtin) = Builtin::from_name(name) {
+            if !builtin.accepts(count) {
+                return Err(ExpressionError::new_argument_count(
+                    name.to_string(),
+                    count,
+                    start,
+                ));
+            }
+            Op::Builtin(builtin, count)
+        } else if self.names.any || self.names.functions.contains(name) {
+            Op::Function(name.into(), count)
+        } else {
+            return Err(ExpressionError::new_undefined_function(
+                name.to_string(),
+                start,
+            ));
+        };
+        self.ops.push(op);
+        Ok(())
+    }
+
+    /// Compiles with `compile` one level deeper, failing beyond [MAX_NESTING] levels so that
+    /// untrusted expressions cannot overflow the stack.
+    fn nested(
+        &mut self,
+        compile: fn(&mut Self) -> Result<(), ExpressionError>,
+    ) -> Result<(), ExpressionError> {
+        if self.nesting == MAX_NESTING {
+            return Err(ExpressionError::new_nesting_too_deep(self.pos));
+        }
+        self.nesting += 1;
+        let res = compile(self);
+        self.nesting -= 1;
+        res
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next_operator(&mut self) -> Option<char> {
+        self.skip_whitespaces();
+        self.peek()
+    }
+
+    fn skip_whitespaces(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn digits(&mut self) -> &'a str {
+        let text = self.text;
+        let start = self.pos;
+        let rest = &text[start..];
+        self.pos += rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        &text[start..self.pos]
+    }
+
+    fn name(&mut self) -> &'a str {
+        let text = self.text;
+        let start = self.pos;
+        let rest = &text[start..];
+        self.pos += rest
+            .find(|c: char| !is_name_start(c) && !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        &text[start..self.pos]
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ExpressionError> {
+        self.skip_whitespaces();
+        self.expect_exactly(expected)
+    }
+
+    fn expect_exactly(&mut self, expected: char) -> Result<(), ExpressionError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            Some(character) => Err(ExpressionError::new_unexpected_character(
+                character, self.pos,
+            )),
+            None => Err(ExpressionError::new_unexpected_end(self.pos)),
+        }
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    struct TestContext;
+
+    impl Context for TestContext {
+        fn parameter(&mut self, index: usize) -> f64 {
+            index as f64 * 10.
+        }
+
+        fn rank(&mut self) -> f64 {
+            0.5
+        }
+
+        fn rand(&mut self) -> f64 {
+            0.25
+        }
+
+        fn loop_index(&mut self, depth: usize) -> f64 {
+            depth as f64 + 100.
+        }
+
+        fn variable(&mut self, name: &str) -> f64 {
+            name.len() as f64
+        }
+
+        fn function(&mut self, _name: &str, args: &[f64]) -> f64 {
+            args.iter().product()
+        }
+    }
+
+    fn eval(text: &str) -> f64 {
+        let names = AppNames {
+            variables: vec!["hp".to_string()].into_iter().collect(),
+            functions: vec!["mul".to_string()].into_iter().collect(),
+            any: false,
+        };
+        compile(text, &names).unwrap().eval(&mut TestContext)
+    }
+
+    fn error(text: &str) -> String {
+        format!("{}", compile(text, &AppNames::default()).unwrap_err())
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("1 + 2 * 3"), 7.);
+        assert_eq!(eval("(1 + 2) * 3"), 9.);
+        assert_eq!(eval("10 - 4 - 3"), 3.);
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.);
+        assert_eq!(eval("-2 ^ 2"), -4.);
+        assert_eq!(eval("2 ^ -1"), 0.5);
+        assert_eq!(eval("7 % 4 / 2"), 1.5);
+        assert_eq!(eval("--+1"), 1.);
+        assert_eq!(eval("1 < 2 == 2 >= 3"), 0.);
+        assert_eq!(eval("(1 + 1 != 2) + (3 <= 3) * 2 + (1 > 0)"), 3.);
+        assert_eq!(eval(".5 + 1.5e1 + 2E-1"), 15.7);
+        assert_eq!(eval("$1 + $2 * $rank + $rand"), 20.25);
+        assert_eq!(eval("$loop.index + $loop.2.index"), 202.);
+        assert_eq!(eval("$hp + mul(2, 3, $1)"), 62.);
+        assert_eq!(eval("min(3, 1, 2) + max(1) + abs(-2) + sign(-3)"), 3.);
+        assert_eq!(eval("int(-1.5) + floor(1.5) + ceil(1.5) + round(2.5)"), 5.);
+        assert_eq!(eval("round(5, 12) + log(100) + log(2, 8)"), 15.);
+        assert_eq!(eval("cos(pi()) + e() - e()"), -1.);
+        // Deeper than the stack which is not allocated.
+        let twos = vec!["2"; INLINE_STACK].join(", ");
+        assert_eq!(eval(&format!("mul($1, {})", twos)), 10. * 2f64.powi(32));
+        assert_eq!(
+            eval(&format!("$1 - mul({}, $1)", twos)),
+            10. - 10. * 2f64.powi(32)
+        );
+        assert_eq!(
+            compile("2 * ($1 + max(1, $3))", &AppNames::default())
+                .unwrap()
+                .max_parameter(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_folding() {
+        let names = AppNames::default();
+        let expr = compile("360 / 16 + 2 * pi() - max(1, 2)", &names).unwrap();
+        assert_eq!(expr.constant(), Some(22.5 + 2. * std::f64::consts::PI - 2.));
+
+        let expr = compile("$1 * (360 / 16) + -$rank", &names).unwrap();
+        assert_eq!(
+            *expr.ops,
+            [
+                Op::Parameter(1),
+                Op::Const(22.5),
+                Op::Mul,
+                Op::Rank,
+                Op::Neg,
+                Op::Add
+            ]
+        );
+        assert_eq!(expr.eval(&mut TestContext), 224.5);
+
+        let expr = compile("$rank * 2 + 1", &names).unwrap();
+        assert_eq!(expr.constant(), None);
+        assert_eq!(expr.specialize_for_rank(0.25).constant(), Some(1.5));
+
+        let expr = compile("$rank * 2 * $rand", &names)
+            .unwrap()
+            .specialize_for_rank(0.25);
+        assert_eq!(*expr.ops, [Op::Const(0.5), Op::Rand, Op::Mul]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error("1 +"), "Unexpected end of expression at offset 3");
+        assert_eq!(error("(1 + 2"), "Unexpected end of expression at offset 6");
+        assert_eq!(error("1 + * 2"), "Unexpected character '*' at offset 4");
+        assert_eq!(error("1 2"), "Unexpected character '2' at offset 2");
+        assert_eq!(error("1 + $hp"), "Undefined variable $hp at offset 4");
+        assert_eq!(error("rank"), "Undefined variable rank at offset 0");
+        assert_eq!(
+            error("2 * angle(1)"),
+            "Undefined function angle at offset 4"
+        );
+        assert_eq!(
+            error("min()"),
+            "Function min does not accept 0 arguments at offset 0"
+        );
+        assert_eq!(error("$0"), "Invalid parameter $0 at offset 0");
+        assert_eq!(error("$loop.x"), "Unexpected character 'x' at offset 6");
+        assert_eq!(
+            error("$loop.1.count"),
+            "Unexpected character 'c' at offset 8"
+        );
+        assert_eq!(error("1 + é"), "Unexpected character 'é' at offset 4");
+        assert_eq!(eval("$300"), 3000.);
+        assert_eq!(
+            error("$99999999999999999999999"),
+            "Invalid parameter $99999999999999999999999 at offset 0"
+        );
+        assert_eq!(
+            error(&"(".repeat(100_000)),
+            "Expression nested too deeply at offset 129"
+        );
+        assert_eq!(
+            error(&"-".repeat(100_000)),
+            "Expression nested too deeply at offset 129"
+        );
+        assert_eq!(
+            eval(&format!("{}1{}", "(".repeat(128), ")".repeat(128))),
+            1.
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_compile_never_panics(text in "\\PC*") {
+            if let Ok(expr) = compile(&text, &AppNames::default()) {
+                expr.eval(&mut TestContext);
+            }
+        }
+
+        #[test]
+        fn test_compile_expression_like_never_panics(text in "[-+*/%^()$.,<>=! 0-9a-z\u{3000}é]{0,40}") {
+            let names = AppNames {
+                any: true,
+                ..AppNames::default()
+            };
+            if let Ok(expr) = compile(&text, &names) {
+                expr.eval(&mut TestContext);
+                expr.specialize_for_rank(1.).eval(&mut TestContext);
+            }
+        }
+    }
+}
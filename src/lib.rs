@@ -6,13 +6,28 @@ extern crate derive_new;
 #[cfg(test)]
 #[macro_use]
 extern crate assert_matches;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_crate;
 #[macro_use]
 extern crate thiserror;
 
+pub use expr::CompiledExpression;
 pub use runner::{AppRunner, Runner, RunnerData, State};
-pub use tree::BulletML;
+pub use tree::{
+    BulletML, BulletMLExpression, BulletMLType, DirectionType, ExpressionSource, HVType, SpeedType,
+};
 
+mod analysis;
+pub mod build;
+mod dtd;
 pub mod errors;
+mod expr;
 pub mod parse;
+mod reload;
 mod runner;
+#[cfg(feature = "serde")]
+mod serialize;
 mod tree;
+pub mod world;
+mod write;
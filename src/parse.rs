@@ -1,38 +1,88 @@
-use crate::errors::{ParseError, ParseErrorPos};
+use crate::analysis;
+use crate::dtd::{self, ContentError};
+use crate::errors::{
+    Diagnostic, ExpressionError, ParseError, ParseErrorPos, ReferenceKind, Severity,
+};
+use crate::expr::{self, AppNames};
 use crate::tree::{
-    BulletML, BulletMLExpression, BulletMLNode, BulletMLType, DirectionType, HVType, SpeedType,
+    BulletML, BulletMLExpression, BulletMLNode, BulletMLType, DirectionType, ExpressionSource,
+    HVType, SpeedType,
 };
+use encoding_rs::{DecoderResult, Encoding};
 use indextree::{Arena, NodeId};
 use roxmltree::TextPos;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io;
 use std::io::prelude::*;
 use std::path;
 
+/// Maximum number of nested actions, and of nested imports, in a document.
+const MAX_NESTING: usize = 128;
+
+/// Resolves the path of an imported document to its content, see
+/// [import_resolver](struct.BulletMLParser.html#method.import_resolver).
+pub type ImportResolver = Box<dyn FnMut(&str) -> Result<String, ParseError>>;
+
 /// BulletML parser.
+///
+/// Expressions are made of numbers, the operators `+`, `-`, `*`, `/`, `%` and `^`, the
+/// comparisons `<`, `<=`, `>`, `>=`, `==` and `!=`, which give 1 or 0, parentheses and the
+/// functions `int`, `ceil`, `floor`, `abs`, `sign`, `log`, `round`, `min`, `max`, `e`,
+/// `pi` and the trigonometric ones, which work with radians.
+///
+/// Besides the parameters `$1`, `$2`, ..., `$rank` and `$rand`, expressions can use
+/// `$loop.index`, the iteration of the innermost running `<repeat>` starting from 0, and
+/// `$loop.N.index` for the repeat N levels above it, `$loop.0.index` being the innermost one.
+/// Loops are those of the running action, including the ones containing the references which led
+/// to it, and evaluate to 0 when there is no such loop.
+///
+/// Application variables and functions can be used as well once registered with
+/// [variable](#method.variable) and [function](#method.function).
 pub struct BulletMLParser {
     arena: Arena<BulletMLNode>,
     bullet_refs: HashMap<String, NodeId>,
     action_refs: HashMap<String, NodeId>,
     fire_refs: HashMap<String, NodeId>,
-    expr_parser: fasteval::Parser,
-    expr_slab: fasteval::Slab,
+    refs: Vec<NodeId>,
+    spans: HashMap<NodeId, (ParseErrorPos, ParseErrorPos)>,
+    diagnostics: Vec<Diagnostic>,
+    recover: bool,
+    allow_unguarded_recursion: bool,
+    validate_structure: bool,
+    import_resolver: Option<ImportResolver>,
+    /// Whether imports must stay in the directory of the importing document.
+    confine_imports: bool,
+    imports: Vec<String>,
+    custom_elements: HashSet<String>,
+    app_names: AppNames,
+    namespace: Option<String>,
+    /// Number of nested actions being parsed.
+    nesting: usize,
 }
 
 impl BulletMLParser {
     /// Creates a new parser with default capacities.
-    ///
-    /// Pay attention to the fact that the capacity of the expression parser cannot grow due to
-    /// `fasteval::Slab` implementation. If you need a higher capacity, refer to the
-    /// [with_capacities](#method.with_capacities) constructor.
     pub fn new() -> Self {
         BulletMLParser {
             arena: Arena::new(),
             bullet_refs: HashMap::new(),
             action_refs: HashMap::new(),
             fire_refs: HashMap::new(),
-            expr_parser: fasteval::Parser::new(),
-            expr_slab: fasteval::Slab::new(),
+            refs: Vec::new(),
+            spans: HashMap::new(),
+            diagnostics: Vec::new(),
+            recover: false,
+            allow_unguarded_recursion: false,
+            validate_structure: false,
+            import_resolver: None,
+            confine_imports: false,
+            imports: Vec::new(),
+            custom_elements: HashSet::new(),
+            app_names: AppNames::default(),
+            namespace: None,
+            nesting: 0,
         }
     }
 
@@ -40,38 +90,184 @@ impl BulletMLParser {
     ///
     /// `refs_capacity` is the initial capacity of references containers which can grow on demand.
     ///
-    /// `expr_capacity` is the capacity of the expression parser which cannot grow. In order to
-    /// mitigate that limitation, the internal of this crate handle float literals without the help
-    /// of the expression parser.
-    pub fn with_capacities(refs_capacity: usize, expr_capacity: usize) -> Self {
+    /// `expr_capacity` is ignored: compiled expressions own their operations and have no capacity.
+    /// It is kept for compatibility.
+    pub fn with_capacities(refs_capacity: usize, _expr_capacity: usize) -> Self {
         BulletMLParser {
             arena: Arena::new(),
             bullet_refs: HashMap::with_capacity(refs_capacity),
             action_refs: HashMap::with_capacity(refs_capacity),
             fire_refs: HashMap::with_capacity(refs_capacity),
-            expr_parser: fasteval::Parser::new(),
-            expr_slab: fasteval::Slab::with_capacity(expr_capacity),
+            refs: Vec::new(),
+            spans: HashMap::new(),
+            diagnostics: Vec::new(),
+            recover: false,
+            allow_unguarded_recursion: false,
+            validate_structure: false,
+            import_resolver: None,
+            confine_imports: false,
+            imports: Vec::new(),
+            custom_elements: HashSet::new(),
+            app_names: AppNames::default(),
+            namespace: None,
+            nesting: 0,
         }
     }
 
+    /// Allows or rejects actions which reference themselves, directly or not, without a
+    /// guaranteed wait in between. They are rejected by default because they make the runner loop
+    /// forever.
+    ///
+    /// A wait is only guaranteed when its number of frames is a constant of at least 1. Allowing
+    /// such recursion is needed when the wait depends on an expression which is known by the author
+    /// to never evaluate to 0.
+    pub fn allow_unguarded_recursion(mut self, allow: bool) -> Self {
+        self.allow_unguarded_recursion = allow;
+        self
+    }
+
+    /// Enables or disables the validation of the document structure against the content models of
+    /// bulletml.dtd. It is disabled by default.
+    ///
+    /// When enabled, missing required children such as the `<term>` of a `<changeDirection>`,
+    /// repeated children and children out of order are reported as errors instead of being
+    /// ignored by the runner.
+    pub fn validate_structure(mut self, validate: bool) -> Self {
+        self.validate_structure = validate;
+        self
+    }
+
+    /// Sets the function loading the documents imported with
+    /// `<import src="patterns.xml" as="patterns" />` elements, children of `<bulletml>`.
+    ///
+    /// The bullets, actions and fires of an imported document are added to the importing one with
+    /// their labels prefixed by the namespace given in the `as` attribute, e.g. `patterns.ring`.
+    /// The references of the imported document are prefixed too, so that it uses its own labels
+    /// unprefixed. Imported documents can import other documents, in which case the namespaces are
+    /// chained.
+    ///
+    /// The resolver is given the `src` attribute resolved against the path of the document
+    /// containing the `<import>`, using `/` as separator: `b.xml` imported by `sub/a.xml` is
+    /// resolved to `sub/b.xml`. The `src` of the parsed document itself is given unchanged, so
+    /// paths are relative to the parsed document.
+    ///
+    /// Without resolver, [parse_file](#method.parse_file) loads the imported files relative to
+    /// the directory of the parsed file and the other parsing methods fail on imports. A custom
+    /// resolver is needed to import files outside of the directory of the importing document.
+    pub fn import_resolver<F>(mut self, resolver: F) -> Self
+    where
+        F: FnMut(&str) -> Result<String, ParseError> + 'static,
+    {
+        self.import_resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Registers an application specific element which can be used in `<action>` elements, e.g.
+    /// `<shake duration="10"><param>$rank * 5</param></shake>` for `custom_element("shake")`.
+    ///
+    /// Such an element becomes a `BulletMLNode::Custom` node keeping its attributes and the
+    /// expressions of its `<param>` children. When run, the parameters are evaluated and passed to
+    /// [AppRunner::do_custom](../trait.AppRunner.html#method.do_custom). The standard elements
+    /// cannot be overridden.
+    pub fn custom_element(mut self, name: &str) -> Self {
+        self.custom_elements.insert(name.to_string());
+        self
+    }
+
+    /// Registers an application variable which can be used in expressions as `$name`, e.g.
+    /// `$playerX` for `variable("playerX")`. Its value is given by
+    /// [AppRunner::get_variable](../trait.AppRunner.html#method.get_variable).
+    ///
+    /// Variables which are not registered are reported as expression errors. The standard
+    /// variables `$rank`, `$rand` and `$loop` cannot be overridden.
+    pub fn variable(mut self, name: &str) -> Self {
+        self.app_names.variables.insert(name.to_string());
+        self
+    }
+
+    /// Registers an application function which can be called in expressions, e.g.
+    /// `angleTo(100, 200)` for `function("angleTo")`. Its result is given by
+    /// [AppRunner::call_function](../trait.AppRunner.html#method.call_function).
+    ///
+    /// Functions which are neither registered nor built in the expression evaluator are reported
+    /// as expression errors. The built-in functions cannot be overridden.
+    pub fn function(mut self, name: &str) -> Self {
+        self.app_names.functions.insert(name.to_string());
+        self
+    }
+
     /// Parses an input XML document and transforms it into a [BulletML](../struct.BulletML.html)
     /// structure to be used by a [Runner](../struct.Runner.html).
-    pub fn parse(mut self, s: &str) -> Result<BulletML, ParseError> {
+    ///
+    /// Any malformed input, including documents nesting actions, imports or expressions too
+    /// deeply, results in an error rather than a panic so that untrusted documents can be parsed.
+    pub fn parse(self, s: &str) -> Result<BulletML, ParseError> {
+        self.parse_with_warnings(s).map(|(bml, _)| bml)
+    }
+
+    /// Parses an input XML document like [parse](#method.parse) and also returns the warnings
+    /// found in the document.
+    ///
+    /// Warnings are non fatal issues such as references passing more parameters than what the
+    /// referenced element uses.
+    pub fn parse_with_warnings(self, s: &str) -> Result<(BulletML, Vec<ParseError>), ParseError> {
+        self.parse_document(s).map(|(bml, diagnostics)| {
+            (
+                bml,
+                diagnostics
+                    .into_iter()
+                    .map(Diagnostic::into_error)
+                    .collect(),
+            )
+        })
+    }
+
+    /// Parses an input XML document like [parse](#method.parse) but carries on after recoverable
+    /// errors in order to report as many issues as possible at once.
+    ///
+    /// Unknown elements and references without label are skipped, unrecognized types fall back to
+    /// their default, invalid expressions are replaced by 0 and unresolved references are removed.
+    /// The resulting document is returned along with diagnostics for all of them, as well as for
+    /// the warnings.
+    ///
+    /// Only errors which prevent from building a document at all, such as malformed XML, are
+    /// returned as `Err`.
+    pub fn parse_with_diagnostics(
+        mut self,
+        s: &str,
+    ) -> Result<(BulletML, Vec<Diagnostic>), ParseError> {
+        self.recover = true;
+        self.parse_document(s)
+    }
+
+    fn parse_document(mut self, s: &str) -> Result<(BulletML, Vec<Diagnostic>), ParseError> {
         let doc = roxmltree::Document::parse(s)?;
+        let root = BulletMLParser::bulletml_root(&doc)?;
+        if self.validate_structure {
+            self.check_structure(root)?;
+        }
+        let root_id = self.parse_bulletml(root)?;
+        self.check_refs()?;
+        Ok((
+            BulletML {
+                arena: self.arena,
+                root: root_id,
+                bullet_refs: self.bullet_refs,
+                action_refs: self.action_refs,
+                fire_refs: self.fire_refs,
+                spans: self.spans,
+            },
+            self.diagnostics,
+        ))
+    }
+
+    fn bulletml_root<'a, 'input>(
+        doc: &'a roxmltree::Document<'input>,
+    ) -> Result<roxmltree::Node<'a, 'input>, ParseError> {
         let root = doc.root_element();
         let root_name = root.tag_name();
         match root_name.name() {
-            "bulletml" => {
-                let root_id = self.parse_bulletml(root)?;
-                Ok(BulletML {
-                    arena: self.arena,
-                    root: root_id,
-                    bullet_refs: self.bullet_refs,
-                    action_refs: self.action_refs,
-                    fire_refs: self.fire_refs,
-                    expr_slab: self.expr_slab,
-                })
-            }
+            "bulletml" => Ok(root),
             name => Err(ParseError::new_unexpected_element(
                 name.to_string(),
                 BulletMLParser::node_pos(&root),
@@ -79,67 +275,349 @@ impl BulletMLParser {
         }
     }
 
-    /// Parses an input XML file and transforms it into a [BulletML](../struct.BulletML.html)
-    /// structure to be used by a [Runner](../struct.Runner.html).
-    pub fn parse_file<P: AsRef<path::Path>>(self, path: P) -> Result<BulletML, ParseError> {
-        let mut file = fs::File::open(&path)?;
-        let mut text = String::new();
-        file.read_to_string(&mut text)?;
+    /// Runs the checks of the references parsed so far, once the labels they can point at are
+    /// known.
+    fn check_refs(&mut self) -> Result<(), ParseError> {
+        self.resolve_refs()?;
+        self.check_parameters()?;
+        if !self.allow_unguarded_recursion {
+            self.check_recursion()?;
+        }
+        Ok(())
+    }
+
+    /// Parses an input XML document given as bytes and transforms it into a
+    /// [BulletML](../struct.BulletML.html) structure to be used by a
+    /// [Runner](../struct.Runner.html).
+    ///
+    /// The encoding is detected from the byte order mark if any, then from the `encoding`
+    /// attribute of the XML declaration, and defaults to UTF-8. That allows legacy encodings such
+    /// as Shift_JIS or EUC-JP.
+    pub fn parse_bytes(self, bytes: &[u8]) -> Result<BulletML, ParseError> {
+        let text = BulletMLParser::decode(bytes)?;
         self.parse(&text)
     }
 
-    fn parse_bulletml(&mut self, bulletml: roxmltree::Node) -> Result<NodeId, ParseError> {
-        let type_att = bulletml.attribute("type");
-        let id = match type_att {
-            Some(type_att) => match type_att {
-                "none" => self
-                    .arena
-                    .new_node(BulletMLNode::BulletML { bml_type: None }),
-                "vertical" => self.arena.new_node(BulletMLNode::BulletML {
-                    bml_type: Some(BulletMLType::Vertical),
-                }),
-                "horizontal" => self.arena.new_node(BulletMLNode::BulletML {
-                    bml_type: Some(BulletMLType::Horizontal),
-                }),
-                _ => {
-                    return Err(ParseError::new_unrecognized_bml_type(
-                        type_att.to_string(),
-                        BulletMLParser::attribute_value_pos(&bulletml, "type"),
-                    ));
+    /// Parses an input XML document read from `reader`. It works the same way as
+    /// [parse_bytes](#method.parse_bytes).
+    pub fn parse_reader<R: Read>(self, mut reader: R) -> Result<BulletML, ParseError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        self.parse_bytes(&bytes)
+    }
+
+    /// Parses an input XML file and transforms it into a [BulletML](../struct.BulletML.html)
+    /// structure to be used by a [Runner](../struct.Runner.html). It works the same way as
+    /// [parse_bytes](#method.parse_bytes).
+    ///
+    /// Unless an [import_resolver](#method.import_resolver) is set, imported files are read
+    /// relative to the directory of the importing document and must stay in it: absolute paths
+    /// and paths going up with `..` are rejected.
+    pub fn parse_file<P: AsRef<path::Path>>(mut self, path: P) -> Result<BulletML, ParseError> {
+        let file = fs::File::open(&path)?;
+        if self.import_resolver.is_none() {
+            let dir = path
+                .as_ref()
+                .parent()
+                .map(path::Path::to_path_buf)
+                .unwrap_or_default();
+            self = self.import_resolver(move |src| {
+                let bytes = fs::read(dir.join(src))?;
+                BulletMLParser::decode(&bytes).map(Cow::into_owned)
+            });
+            self.confine_imports = true;
+        }
+        self.parse_reader(file)
+    }
+
+    /// Decodes a BulletML document given as bytes the same way as
+    /// [parse_bytes](#method.parse_bytes) does. This is handy to write an
+    /// [import_resolver](#method.import_resolver) reading documents in legacy encodings.
+    pub fn decode(bytes: &[u8]) -> Result<Cow<'_, str>, ParseError> {
+        let (encoding, bom_len) = match Encoding::for_bom(bytes) {
+            Some((encoding, bom_len)) => (encoding, bom_len),
+            None => match BulletMLParser::declared_encoding(bytes) {
+                Some(label) => {
+                    let encoding = Encoding::for_label(label).ok_or_else(|| {
+                        ParseError::new_unknown_encoding(String::from_utf8_lossy(label).to_string())
+                    })?;
+                    // The declaration could be read as ASCII so the document cannot be UTF-16.
+                    (
+                        if encoding.is_ascii_compatible() {
+                            encoding
+                        } else {
+                            encoding_rs::UTF_8
+                        },
+                        0,
+                    )
                 }
+                None => (encoding_rs::UTF_8, 0),
             },
-            None => self
-                .arena
-                .new_node(BulletMLNode::BulletML { bml_type: None }),
         };
+        let bytes = &bytes[bom_len..];
+        if let Some(text) = encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+            return Ok(text);
+        }
+        // Decode again to find out where the error is.
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut text = String::with_capacity(
+            decoder
+                .max_utf8_buffer_length_without_replacement(bytes.len())
+                .unwrap_or_default(),
+        );
+        let (result, read) = decoder.decode_to_string_without_replacement(bytes, &mut text, true);
+        let offset = match result {
+            DecoderResult::Malformed(bad, after) => {
+                bom_len + read - usize::from(bad) - usize::from(after)
+            }
+            DecoderResult::InputEmpty | DecoderResult::OutputFull => bom_len + read,
+        };
+        Err(ParseError::new_decoding(
+            encoding.name().to_string(),
+            offset,
+        ))
+    }
+
+    fn declared_encoding(bytes: &[u8]) -> Option<&[u8]> {
+        if !bytes.starts_with(b"<?xml") {
+            return None;
+        }
+        let end = bytes.windows(2).position(|w| w == b"?>")?;
+        let re = regex::bytes::Regex::new("encoding\\s*=\\s*(?:\"([^\"]*)\"|'([^']*)')").unwrap();
+        let captures = re.captures(&bytes[..end])?;
+        captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .map(|label| label.as_bytes())
+    }
+
+    /// Records `err` as a diagnostic and carries on when collecting diagnostics, fails with it
+    /// otherwise.
+    fn recover(&mut self, err: ParseError) -> Result<(), ParseError> {
+        if self.recover {
+            self.diagnostics.push(Diagnostic::new(Severity::Error, err));
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    fn check_structure(&mut self, root: roxmltree::Node) -> Result<(), ParseError> {
+        for node in root.descendants().filter(|n| n.is_element()) {
+            let element = node.tag_name().name();
+            let res = dtd::check_content(
+                element,
+                node.children()
+                    .filter(|n| n.is_element())
+                    .map(|child| (child.tag_name().name(), BulletMLParser::node_pos(&child))),
+            );
+            let err = match res {
+                Ok(()) => continue,
+                Err(ContentError::Missing { expected }) => ParseError::new_missing_element(
+                    expected,
+                    element.to_string(),
+                    BulletMLParser::node_pos(&node),
+                ),
+                Err(ContentError::Misplaced { child, pos }) => {
+                    ParseError::new_misplaced_element(child.to_string(), element.to_string(), pos)
+                }
+            };
+            self.recover(err)?;
+        }
+        Ok(())
+    }
+
+    fn parse_bulletml(&mut self, bulletml: roxmltree::Node) -> Result<NodeId, ParseError> {
+        let type_att = bulletml.attribute("type");
+        let bml_type = match type_att {
+            Some("none") | None => None,
+            Some("vertical") => Some(BulletMLType::Vertical),
+            Some("horizontal") => Some(BulletMLType::Horizontal),
+            Some(type_att) => {
+                self.recover(ParseError::new_unrecognized_bml_type(
+                    type_att.to_string(),
+                    BulletMLParser::attribute_value_pos(&bulletml, "type"),
+                ))?;
+                None
+            }
+        };
+        let id = self.new_node(&bulletml, BulletMLNode::BulletML { bml_type });
+        self.parse_definitions(bulletml, id)?;
+        Ok(id)
+    }
+
+    /// Parses the children of a `<bulletml>` element and appends them to `id`.
+    fn parse_definitions(
+        &mut self,
+        bulletml: roxmltree::Node,
+        id: NodeId,
+    ) -> Result<(), ParseError> {
         for child in bulletml.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "bullet" => self.parse_bullet(child)?,
                 "action" => self.parse_action(child)?,
                 "fire" => self.parse_fire(child)?,
+                "import" => {
+                    self.parse_import(child, id)?;
+                    continue;
+                }
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
         }
-        Ok(id)
+        Ok(())
+    }
+
+    fn parse_import(&mut self, import: roxmltree::Node, parent: NodeId) -> Result<(), ParseError> {
+        let pos = BulletMLParser::node_pos(&import);
+        let (src, prefix) = match (import.attribute("src"), import.attribute("as")) {
+            (Some(src), Some(prefix)) => (src, prefix),
+            (src, _) => {
+                self.recover(ParseError::new_missing_attribute(
+                    if src.is_none() { "src" } else { "as" }.to_string(),
+                    import.tag_name().name().to_string(),
+                    pos,
+                ))?;
+                return Ok(());
+            }
+        };
+        let path = BulletMLParser::import_path(self.imports.last().map(String::as_str), src);
+        if self.imports.contains(&path) {
+            self.recover(ParseError::new_import_cycle(path, pos))?;
+            return Ok(());
+        }
+        if self.imports.len() == MAX_NESTING {
+            self.recover(ParseError::new_nesting_too_deep(
+                import.tag_name().name().to_string(),
+                pos,
+            ))?;
+            return Ok(());
+        }
+        let text = match self.import_resolver.as_mut() {
+            Some(_) if self.confine_imports && !BulletMLParser::is_confined(src) => {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "import outside of directory",
+                )
+                .into())
+            }
+            Some(resolve) => resolve(&path),
+            None => Err(io::Error::new(io::ErrorKind::Unsupported, "no import resolver").into()),
+        };
+        let namespace = self.qualify(prefix);
+
+        // The imported document is checked on its own: its references cannot point at the labels
+        // of the importing document.
+        self.imports.push(path);
+        let outer_namespace = self.namespace.replace(namespace);
+        let outer_refs = std::mem::take(&mut self.refs);
+        let diagnostics_len = self.diagnostics.len();
+        let res = text.and_then(|text| self.parse_imported(&text, parent));
+        self.refs = outer_refs;
+        self.namespace = outer_namespace;
+        self.imports.pop();
+
+        let wrap = |err| ParseError::new_import(src.to_string(), pos, Box::new(err));
+        let diagnostics = self
+            .diagnostics
+            .drain(diagnostics_len..)
+            .map(|diagnostic| Diagnostic::new(diagnostic.severity(), wrap(diagnostic.into_error())))
+            .collect::<Vec<_>>();
+        self.diagnostics.extend(diagnostics);
+        if let Err(err) = res {
+            self.recover(wrap(err))?;
+        }
+        Ok(())
+    }
+
+    /// Resolves `src` against the path of the `importing` document, or leaves it unchanged for the
+    /// parsed document.
+    fn import_path(importing: Option<&str>, src: &str) -> String {
+        if path::Path::new(src).is_absolute() {
+            return src.to_string();
+        }
+        let mut components = match importing {
+            Some(importing) => {
+                let mut components = importing.split('/').collect::<Vec<_>>();
+                // Keeps the directory only.
+                components.pop();
+                components
+            }
+            None => Vec::new(),
+        };
+        for component in src.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => match components.last() {
+                    // The root of an absolute path.
+                    Some(&"") if components.len() == 1 => {}
+                    Some(last) if *last != ".." => {
+                        components.pop();
+                    }
+                    _ => components.push(component),
+                },
+                _ => components.push(component),
+            }
+        }
+        components.join("/")
+    }
+
+    /// Whether `src` stays in the directory of the importing document.
+    fn is_confined(src: &str) -> bool {
+        let mut depth = 0usize;
+        path::Path::new(src)
+            .components()
+            .all(|component| match component {
+                path::Component::Normal(_) => {
+                    depth += 1;
+                    true
+                }
+                path::Component::CurDir => true,
+                path::Component::ParentDir => match depth.checked_sub(1) {
+                    Some(parent) => {
+                        depth = parent;
+                        true
+                    }
+                    None => false,
+                },
+                path::Component::Prefix(_) | path::Component::RootDir => false,
+            })
+    }
+
+    fn parse_imported(&mut self, s: &str, parent: NodeId) -> Result<(), ParseError> {
+        let doc = roxmltree::Document::parse(s)?;
+        let root = BulletMLParser::bulletml_root(&doc)?;
+        if self.validate_structure {
+            self.check_structure(root)?;
+        }
+        self.parse_definitions(root, parent)?;
+        self.check_refs()
+    }
+
+    /// Prefixes `label` with the namespace of the document being imported, if any.
+    fn qualify(&self, label: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}.{}", namespace, label),
+            None => label.to_string(),
+        }
     }
 
     fn parse_bullet(&mut self, bullet: roxmltree::Node) -> Result<NodeId, ParseError> {
         let label = bullet.attribute("label");
         let id = if let Some(label) = label {
-            let id = self
-                .arena
-                .new_node(BulletMLNode::Bullet(Some(label.to_string())));
-            self.bullet_refs.insert(label.to_string(), id);
+            let label = self.qualify(label);
+            let id = self.new_node(&bullet, BulletMLNode::Bullet(Some(label.clone())));
+            self.bullet_refs.insert(label, id);
             id
         } else {
-            self.arena.new_node(BulletMLNode::Bullet(None))
+            self.new_node(&bullet, BulletMLNode::Bullet(None))
         };
         for child in bullet.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
@@ -147,12 +625,16 @@ impl BulletMLParser {
                 "direction" => self.parse_direction(child)?,
                 "speed" => self.parse_speed(child)?,
                 "action" => self.parse_action(child)?,
-                "actionRef" => self.parse_action_ref(child)?,
+                "actionRef" => match self.parse_action_ref(child)? {
+                    Some(child_id) => child_id,
+                    None => continue,
+                },
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
@@ -160,35 +642,57 @@ impl BulletMLParser {
         Ok(id)
     }
 
+    /// Parses an action, failing beyond [MAX_NESTING] nested actions so that untrusted documents
+    /// cannot overflow the stack.
     fn parse_action(&mut self, action: roxmltree::Node) -> Result<NodeId, ParseError> {
+        if self.nesting == MAX_NESTING {
+            return Err(ParseError::new_nesting_too_deep(
+                action.tag_name().name().to_string(),
+                BulletMLParser::node_pos(&action),
+            ));
+        }
+        self.nesting += 1;
+        let res = self.parse_action_content(action);
+        self.nesting -= 1;
+        res
+    }
+
+    fn parse_action_content(&mut self, action: roxmltree::Node) -> Result<NodeId, ParseError> {
         let label = action.attribute("label");
         let id = if let Some(label) = label {
-            let id = self
-                .arena
-                .new_node(BulletMLNode::Action(Some(label.to_string())));
-            self.action_refs.insert(label.to_string(), id);
+            let label = self.qualify(label);
+            let id = self.new_node(&action, BulletMLNode::Action(Some(label.clone())));
+            self.action_refs.insert(label, id);
             id
         } else {
-            self.arena.new_node(BulletMLNode::Action(None))
+            self.new_node(&action, BulletMLNode::Action(None))
         };
         for child in action.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "repeat" => self.parse_repeat(child)?,
                 "fire" => self.parse_fire(child)?,
-                "fireRef" => self.parse_fire_ref(child)?,
+                "fireRef" => match self.parse_fire_ref(child)? {
+                    Some(child_id) => child_id,
+                    None => continue,
+                },
                 "changeSpeed" => self.parse_change_speed(child)?,
                 "changeDirection" => self.parse_change_direction(child)?,
                 "accel" => self.parse_accel(child)?,
                 "wait" => self.parse_wait(child)?,
                 "vanish" => self.parse_vanish(child)?,
                 "action" => self.parse_action(child)?,
-                "actionRef" => self.parse_action_ref(child)?,
+                "actionRef" => match self.parse_action_ref(child)? {
+                    Some(child_id) => child_id,
+                    None => continue,
+                },
+                name if self.custom_elements.contains(name) => self.parse_custom(child)?,
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
@@ -199,13 +703,12 @@ impl BulletMLParser {
     fn parse_fire(&mut self, fire: roxmltree::Node) -> Result<NodeId, ParseError> {
         let label = fire.attribute("label");
         let id = if let Some(label) = label {
-            let id = self
-                .arena
-                .new_node(BulletMLNode::Fire(Some(label.to_string())));
-            self.fire_refs.insert(label.to_string(), id);
+            let label = self.qualify(label);
+            let id = self.new_node(&fire, BulletMLNode::Fire(Some(label.clone())));
+            self.fire_refs.insert(label, id);
             id
         } else {
-            self.arena.new_node(BulletMLNode::Fire(None))
+            self.new_node(&fire, BulletMLNode::Fire(None))
         };
         for child in fire.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
@@ -213,12 +716,16 @@ impl BulletMLParser {
                 "direction" => self.parse_direction(child)?,
                 "speed" => self.parse_speed(child)?,
                 "bullet" => self.parse_bullet(child)?,
-                "bulletRef" => self.parse_bullet_ref(child)?,
+                "bulletRef" => match self.parse_bullet_ref(child)? {
+                    Some(child_id) => child_id,
+                    None => continue,
+                },
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
@@ -230,17 +737,18 @@ impl BulletMLParser {
         &mut self,
         change_direction: roxmltree::Node,
     ) -> Result<NodeId, ParseError> {
-        let id = self.arena.new_node(BulletMLNode::ChangeDirection);
+        let id = self.new_node(&change_direction, BulletMLNode::ChangeDirection);
         for child in change_direction.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "direction" => self.parse_direction(child)?,
                 "term" => self.parse_term(child)?,
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
@@ -249,17 +757,18 @@ impl BulletMLParser {
     }
 
     fn parse_change_speed(&mut self, change_speed: roxmltree::Node) -> Result<NodeId, ParseError> {
-        let id = self.arena.new_node(BulletMLNode::ChangeSpeed);
+        let id = self.new_node(&change_speed, BulletMLNode::ChangeSpeed);
         for child in change_speed.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "speed" => self.parse_speed(child)?,
                 "term" => self.parse_term(child)?,
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
@@ -268,7 +777,7 @@ impl BulletMLParser {
     }
 
     fn parse_accel(&mut self, accel: roxmltree::Node) -> Result<NodeId, ParseError> {
-        let id = self.arena.new_node(BulletMLNode::Accel);
+        let id = self.new_node(&accel, BulletMLNode::Accel);
         for child in accel.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
@@ -276,10 +785,11 @@ impl BulletMLParser {
                 "vertical" => self.parse_vertical(child)?,
                 "term" => self.parse_term(child)?,
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
@@ -289,28 +799,40 @@ impl BulletMLParser {
 
     fn parse_wait(&mut self, wait: roxmltree::Node) -> Result<NodeId, ParseError> {
         let expr = self.parse_expression(wait)?;
-        let id = self.arena.new_node(BulletMLNode::Wait(expr));
+        let id = self.new_node(&wait, BulletMLNode::Wait(expr));
         Ok(id)
     }
 
-    fn parse_vanish(&mut self, _vanish: roxmltree::Node) -> Result<NodeId, ParseError> {
-        let id = self.arena.new_node(BulletMLNode::Vanish);
+    fn parse_vanish(&mut self, vanish: roxmltree::Node) -> Result<NodeId, ParseError> {
+        if self.validate_structure {
+            for child in vanish.children().filter(|n| n.is_element()) {
+                self.recover(ParseError::new_unexpected_element(
+                    child.tag_name().name().to_string(),
+                    BulletMLParser::node_pos(&child),
+                ))?;
+            }
+        }
+        let id = self.new_node(&vanish, BulletMLNode::Vanish);
         Ok(id)
     }
 
     fn parse_repeat(&mut self, repeat: roxmltree::Node) -> Result<NodeId, ParseError> {
-        let id = self.arena.new_node(BulletMLNode::Repeat);
+        let id = self.new_node(&repeat, BulletMLNode::Repeat);
         for child in repeat.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "times" => self.parse_times(child)?,
                 "action" => self.parse_action(child)?,
-                "actionRef" => self.parse_action_ref(child)?,
+                "actionRef" => match self.parse_action_ref(child)? {
+                    Some(child_id) => child_id,
+                    None => continue,
+                },
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
@@ -327,17 +849,21 @@ impl BulletMLParser {
             Some("sequence") => Some(DirectionType::Sequence),
             None => None,
             Some(type_att) => {
-                return Err(ParseError::new_unrecognized_direction_type(
+                self.recover(ParseError::new_unrecognized_direction_type(
                     type_att.to_string(),
                     BulletMLParser::attribute_value_pos(&direction, "type"),
-                ));
+                ))?;
+                None
             }
         };
         let expr = self.parse_expression(direction)?;
-        let id = self.arena.new_node(BulletMLNode::Direction {
-            dir_type,
-            dir: expr,
-        });
+        let id = self.new_node(
+            &direction,
+            BulletMLNode::Direction {
+                dir_type,
+                dir: expr,
+            },
+        );
         Ok(id)
     }
 
@@ -349,17 +875,21 @@ impl BulletMLParser {
             Some("sequence") => Some(SpeedType::Sequence),
             None => None,
             Some(type_att) => {
-                return Err(ParseError::new_unrecognized_speed_type(
+                self.recover(ParseError::new_unrecognized_speed_type(
                     type_att.to_string(),
                     BulletMLParser::attribute_value_pos(&speed, "type"),
-                ));
+                ))?;
+                None
             }
         };
         let expr = self.parse_expression(speed)?;
-        let id = self.arena.new_node(BulletMLNode::Speed {
-            spd_type,
-            spd: expr,
-        });
+        let id = self.new_node(
+            &speed,
+            BulletMLNode::Speed {
+                spd_type,
+                spd: expr,
+            },
+        );
         Ok(id)
     }
 
@@ -370,16 +900,15 @@ impl BulletMLParser {
             Some("relative") => HVType::Relative,
             Some("sequence") => HVType::Sequence,
             Some(type_att) => {
-                return Err(ParseError::new_unrecognized_accel_dir_type(
+                self.recover(ParseError::new_unrecognized_accel_dir_type(
                     type_att.to_string(),
                     BulletMLParser::attribute_value_pos(&horizontal, "type"),
-                ));
+                ))?;
+                HVType::Absolute
             }
         };
         let expr = self.parse_expression(horizontal)?;
-        let id = self
-            .arena
-            .new_node(BulletMLNode::Horizontal { h_type, h: expr });
+        let id = self.new_node(&horizontal, BulletMLNode::Horizontal { h_type, h: expr });
         Ok(id)
     }
 
@@ -390,177 +919,361 @@ impl BulletMLParser {
             Some("relative") => HVType::Relative,
             Some("sequence") => HVType::Sequence,
             Some(type_att) => {
-                return Err(ParseError::new_unrecognized_accel_dir_type(
+                self.recover(ParseError::new_unrecognized_accel_dir_type(
                     type_att.to_string(),
                     BulletMLParser::attribute_value_pos(&vertical, "type"),
-                ));
+                ))?;
+                HVType::Absolute
             }
         };
         let expr = self.parse_expression(vertical)?;
-        let id = self
-            .arena
-            .new_node(BulletMLNode::Vertical { v_type, v: expr });
+        let id = self.new_node(&vertical, BulletMLNode::Vertical { v_type, v: expr });
         Ok(id)
     }
 
     fn parse_term(&mut self, term: roxmltree::Node) -> Result<NodeId, ParseError> {
         let expr = self.parse_expression(term)?;
-        let id = self.arena.new_node(BulletMLNode::Term(expr));
+        let id = self.new_node(&term, BulletMLNode::Term(expr));
         Ok(id)
     }
 
     fn parse_times(&mut self, times: roxmltree::Node) -> Result<NodeId, ParseError> {
         let expr = self.parse_expression(times)?;
-        let id = self.arena.new_node(BulletMLNode::Times(expr));
+        let id = self.new_node(&times, BulletMLNode::Times(expr));
         Ok(id)
     }
 
-    fn parse_bullet_ref(&mut self, bullet_ref: roxmltree::Node) -> Result<NodeId, ParseError> {
+    fn parse_bullet_ref(
+        &mut self,
+        bullet_ref: roxmltree::Node,
+    ) -> Result<Option<NodeId>, ParseError> {
         let label = bullet_ref.attribute("label");
         let label = if let Some(label) = label {
             label
         } else {
-            return Err(ParseError::new_missing_attribute(
+            self.recover(ParseError::new_missing_attribute(
                 "label".to_string(),
                 bullet_ref.tag_name().name().to_string(),
                 BulletMLParser::node_pos(&bullet_ref),
-            ));
+            ))?;
+            return Ok(None);
         };
-        let id = self
-            .arena
-            .new_node(BulletMLNode::BulletRef(label.to_string()));
+        let label = self.qualify(label);
+        let id = self.new_node(&bullet_ref, BulletMLNode::BulletRef(label));
+        self.refs.push(id);
         for child in bullet_ref.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "param" => self.parse_param(child)?,
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
         }
-        Ok(id)
+        Ok(Some(id))
     }
 
-    fn parse_action_ref(&mut self, action_ref: roxmltree::Node) -> Result<NodeId, ParseError> {
+    fn parse_action_ref(
+        &mut self,
+        action_ref: roxmltree::Node,
+    ) -> Result<Option<NodeId>, ParseError> {
         let label = action_ref.attribute("label");
         let label = if let Some(label) = label {
             label
         } else {
-            return Err(ParseError::new_missing_attribute(
+            self.recover(ParseError::new_missing_attribute(
                 "label".to_string(),
                 action_ref.tag_name().name().to_string(),
                 BulletMLParser::node_pos(&action_ref),
-            ));
+            ))?;
+            return Ok(None);
         };
-        let id = self
-            .arena
-            .new_node(BulletMLNode::ActionRef(label.to_string()));
+        let label = self.qualify(label);
+        let id = self.new_node(&action_ref, BulletMLNode::ActionRef(label));
+        self.refs.push(id);
         for child in action_ref.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "param" => self.parse_param(child)?,
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
         }
-        Ok(id)
+        Ok(Some(id))
     }
 
-    fn parse_fire_ref(&mut self, fire_ref: roxmltree::Node) -> Result<NodeId, ParseError> {
+    fn parse_fire_ref(&mut self, fire_ref: roxmltree::Node) -> Result<Option<NodeId>, ParseError> {
         let label = fire_ref.attribute("label");
         let label = if let Some(label) = label {
             label
         } else {
-            return Err(ParseError::new_missing_attribute(
+            self.recover(ParseError::new_missing_attribute(
                 "label".to_string(),
                 fire_ref.tag_name().name().to_string(),
                 BulletMLParser::node_pos(&fire_ref),
-            ));
+            ))?;
+            return Ok(None);
         };
-        let id = self
-            .arena
-            .new_node(BulletMLNode::FireRef(label.to_string()));
+        let label = self.qualify(label);
+        let id = self.new_node(&fire_ref, BulletMLNode::FireRef(label));
+        self.refs.push(id);
         for child in fire_ref.children().filter(|n| n.is_element()) {
             let child_name = child.tag_name();
             let child_id = match child_name.name() {
                 "param" => self.parse_param(child)?,
                 name => {
-                    return Err(ParseError::new_unexpected_element(
+                    self.recover(ParseError::new_unexpected_element(
                         name.to_string(),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
+                    continue;
                 }
             };
             id.append(child_id, &mut self.arena);
         }
+        Ok(Some(id))
+    }
+
+    fn parse_custom(&mut self, custom: roxmltree::Node) -> Result<NodeId, ParseError> {
+        let attributes = custom
+            .attributes()
+            .iter()
+            .map(|attribute| (attribute.name().to_string(), attribute.value().to_string()))
+            .collect();
+        let mut params = Vec::new();
+        for child in custom.children().filter(|n| n.is_element()) {
+            match child.tag_name().name() {
+                "param" => params.push(self.parse_expression(child)?),
+                name => {
+                    self.recover(ParseError::new_unexpected_element(
+                        name.to_string(),
+                        BulletMLParser::node_pos(&child),
+                    ))?;
+                }
+            }
+        }
+        let id = self.new_node(
+            &custom,
+            BulletMLNode::Custom {
+                name: custom.tag_name().name().to_string(),
+                attributes,
+                params,
+            },
+        );
         Ok(id)
     }
 
     fn parse_param(&mut self, param: roxmltree::Node) -> Result<NodeId, ParseError> {
         let expr = self.parse_expression(param)?;
-        let id = self.arena.new_node(BulletMLNode::Param(expr));
+        let id = self.new_node(&param, BulletMLNode::Param(expr));
         Ok(id)
     }
 
+    /// Checks that every reference points at an existing labelled element of the right kind.
+    ///
+    /// When collecting diagnostics, unresolved references are removed from the document.
+    fn resolve_refs(&mut self) -> Result<(), ParseError> {
+        let mut unresolved = Vec::new();
+        for id in &self.refs {
+            if let Some((kind, label)) = self.arena[*id].get().match_ref() {
+                let labels = match kind {
+                    ReferenceKind::Bullet => &self.bullet_refs,
+                    ReferenceKind::Action => &self.action_refs,
+                    ReferenceKind::Fire => &self.fire_refs,
+                };
+                if !labels.contains_key(label) {
+                    unresolved.push((
+                        *id,
+                        ParseError::new_unresolved_reference(
+                            label.to_string(),
+                            kind,
+                            self.spans[id].0,
+                        ),
+                    ));
+                }
+            }
+        }
+        for (id, err) in unresolved {
+            self.recover(err)?;
+            id.detach(&mut self.arena);
+        }
+        Ok(())
+    }
+
+    /// Checks that every reference passes as many parameters as the referenced element uses.
+    ///
+    /// Passing too few parameters is an error, passing too many is a warning.
+    fn check_parameters(&mut self) -> Result<(), ParseError> {
+        let mut max_parameters = HashMap::new();
+        let mut errors = Vec::new();
+        for id in &self.refs {
+            if let Some((kind, label)) = self.arena[*id].get().match_ref() {
+                let labels = match kind {
+                    ReferenceKind::Bullet => &self.bullet_refs,
+                    ReferenceKind::Action => &self.action_refs,
+                    ReferenceKind::Fire => &self.fire_refs,
+                };
+                let target = if let Some(target) = labels.get(label) {
+                    *target
+                } else {
+                    continue;
+                };
+                let expected = *max_parameters
+                    .entry(target)
+                    .or_insert_with(|| analysis::max_parameter(&self.arena, target));
+                let found = analysis::parameter_count(&self.arena, *id);
+                if found != expected {
+                    let err = ParseError::new_parameter_count(
+                        label.to_string(),
+                        kind,
+                        expected,
+                        found,
+                        self.spans[id].0,
+                    );
+                    if found < expected {
+                        errors.push(err);
+                    } else {
+                        self.diagnostics
+                            .push(Diagnostic::new(Severity::Warning, err));
+                    }
+                }
+            }
+        }
+        for err in errors {
+            self.recover(err)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that no action recurses into itself without waiting.
+    ///
+    /// When collecting diagnostics, the reference closing each cycle is removed from the document.
+    fn check_recursion(&mut self) -> Result<(), ParseError> {
+        while let Some(ref_id) = analysis::find_unguarded_cycle(&self.arena, &self.action_refs) {
+            let err = if let Some((kind, label)) = self.arena[ref_id].get().match_ref() {
+                ParseError::new_unguarded_recursion(label.to_string(), kind, self.spans[&ref_id].0)
+            } else {
+                break;
+            };
+            self.recover(err)?;
+            ref_id.detach(&mut self.arena);
+        }
+        Ok(())
+    }
+
     fn parse_expression(
         &mut self,
         parent: roxmltree::Node,
     ) -> Result<BulletMLExpression, ParseError> {
         let mut str: String = String::new();
+        let mut span = None;
+        // Offsets of the text nodes in `str` and their positions in the document.
+        let mut offsets = Vec::new();
         for child in parent.children() {
             let node_type = child.node_type();
             match node_type {
                 roxmltree::NodeType::Text => {
-                    str.push_str(child.text().unwrap());
+                    let text = child.text().unwrap_or_default();
+                    let text_pos = BulletMLParser::node_pos(&child);
+                    offsets.push((str.len(), text_pos));
+                    str.push_str(text);
+                    let trimmed = text.trim();
+                    if !trimmed.is_empty() {
+                        // Positions are computed from the text rather than from byte offsets in
+                        // the document, which entities shift.
+                        let start = text.len() - text.trim_start().len();
+                        let end = start + trimmed.len();
+                        span = Some((
+                            span.map_or_else(
+                                || text_pos.advance(&text[..start]),
+                                |(start, _)| start,
+                            ),
+                            text_pos.advance(&text[..end]),
+                        ));
+                    }
                 }
                 roxmltree::NodeType::Root | roxmltree::NodeType::Element => {
-                    return Err(ParseError::new_unexpected_node_type(
+                    self.recover(ParseError::new_unexpected_node_type(
                         format!("{:?}", node_type),
                         BulletMLParser::node_pos(&child),
-                    ));
+                    ))?;
                 }
                 roxmltree::NodeType::Comment | roxmltree::NodeType::PI => {}
             }
         }
 
-        let constant = str.parse();
-        if let Ok(constant) = constant {
-            return Ok(BulletMLExpression::Const(constant));
+        let text = str.trim();
+        match self.expression(text, span) {
+            Ok(expr) => Ok(expr),
+            Err(err) => {
+                // Locates the error in the text node it comes from. Entities make the location
+                // approximate.
+                let offset = (str.len() - str.trim_start().len()) + err.offset();
+                let pos = match offsets.iter().rev().find(|(start, _)| *start <= offset) {
+                    Some((start, text_pos)) => {
+                        text_pos.advance(str.get(*start..offset).unwrap_or_default())
+                    }
+                    None => {
+                        BulletMLParser::node_pos(parent.first_child().as_ref().unwrap_or(&parent))
+                    }
+                };
+                self.recover(ParseError::new_expression(err, text.to_string(), pos))?;
+                Ok(BulletMLExpression::Const(0.))
+            }
         }
+    }
 
-        let re = regex::Regex::new("\\$([0-9]+|rank|rand)").unwrap();
-        let str = re.replace_all(&str, |captures: &regex::Captures| match &captures[1] {
-            "rank" => "rank".to_string(),
-            "rand" => "rand()".to_string(),
-            v => {
-                let maybe_num = v.parse::<u8>();
-                match maybe_num {
-                    Ok(num) => format!("v({})", num),
-                    Err(..) => {
-                        panic!("Unrecognized variable pattern ${}", v);
-                    }
-                }
+    /// Turns the trimmed `text` into a constant if it is a number written as the constant would be
+    /// written back, e.g. `1.5` but not `1.50`, compiles it otherwise. The compiled expression is
+    /// folded to a constant if it depends on no variable, e.g. `360 / 16` or `1.50`, but keeps its
+    /// source so that it is written back unchanged.
+    pub(crate) fn expression(
+        &self,
+        text: &str,
+        span: Option<(ParseErrorPos, ParseErrorPos)>,
+    ) -> Result<BulletMLExpression, ExpressionError> {
+        let constant = text.parse::<f64>().ok();
+        if let Some(constant) = constant {
+            if constant.to_string() == text {
+                return Ok(BulletMLExpression::Const(constant));
             }
-        });
-        let expr_ref = self
-            .expr_parser
-            .parse_noclear(&str, &mut self.expr_slab.ps)
-            .map_err(|err| {
-                ParseError::new_expression(
-                    err,
-                    BulletMLParser::node_pos(parent.first_child().as_ref().unwrap_or(&parent)),
-                )
-            })?;
-        Ok(BulletMLExpression::Expr(expr_ref))
+        }
+        let expr = match (expr::compile(text, &self.app_names), constant) {
+            (Ok(expr), _) => expr,
+            // Other spellings of infinity and NaN, e.g. `Infinity` or `nan`, are not expressions.
+            (Err(_), Some(constant)) => return Ok(BulletMLExpression::Const(constant)),
+            (Err(err), None) => return Err(err),
+        };
+        Ok(BulletMLExpression::Expr {
+            expr,
+            source: ExpressionSource::new(text.to_string(), span),
+        })
+    }
+
+    /// Creates a new node in the arena and records the span of the XML element it comes from.
+    fn new_node(&mut self, xml_node: &roxmltree::Node, node: BulletMLNode) -> NodeId {
+        let id = self.arena.new_node(node);
+        let range = xml_node.range();
+        let doc = xml_node.document();
+        self.spans.insert(
+            id,
+            (
+                doc.text_pos_at(range.start).into(),
+                doc.text_pos_at(range.end).into(),
+            ),
+        );
+        id
     }
 
     #[inline]
@@ -587,6 +1300,8 @@ impl Default for BulletMLParser {
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::select;
 
     #[test]
     fn test_bulletml() {
@@ -652,6 +1367,7 @@ mod tests {
     fn test_full_bulletml() {
         // This covers all the good branches of the parser.
         BulletMLParser::new()
+            .allow_unguarded_recursion(true)
             .parse(
                 r##"<?xml version="1.0" ?>
 <bulletml>
@@ -845,6 +1561,59 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_custom_element() {
+        let doc = r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <actionRef label="quake">
+            <param>4</param>
+        </actionRef>
+    </action>
+    <action label="quake">
+        <shake duration="10" axis="x">
+            <param>$1 * 2</param>
+            <param>3</param>
+        </shake>
+    </action>
+</bulletml>"##;
+        let bml = BulletMLParser::new()
+            .custom_element("shake")
+            .parse(doc)
+            .unwrap();
+        let shake = bml.action_refs["quake"]
+            .children(&bml.arena)
+            .next()
+            .unwrap();
+        assert_eq!(
+            format!("{:?}", bml.arena[shake].get()),
+            r#"Custom { name: "shake", attributes: [("duration", "10"), ("axis", "x")], params: [Expr("$1 * 2"), Const(3.0)] }"#
+        );
+
+        assert_matches!(
+            BulletMLParser::new().parse(doc),
+            Err(ParseError::UnexpectedElement { ref element, .. }) if element == "shake"
+        );
+
+        let err = BulletMLParser::new()
+            .custom_element("shake")
+            .parse(&doc.replace("<param>4</param>", ""))
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "Label quake in element actionRef expects 1 parameters but 0 are passed at position 4:9"
+        );
+
+        let err = BulletMLParser::new()
+            .custom_element("shake")
+            .parse(&doc.replace("<param>3</param>", "<wait>3</wait>"))
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "Unexpected element wait at position 11:13"
+        );
+    }
+
     #[test]
     fn test_unexpected_fire_child() {
         let bml = BulletMLParser::new().parse(
@@ -1290,61 +2059,1430 @@ mod tests {
     }
 
     #[test]
-    fn test_unexpected_node_type_in_expression() {
+    fn test_unresolved_bullet_ref() {
         let bml = BulletMLParser::new().parse(
             r##"<?xml version="1.0" ?>
 <bulletml>
-    <bullet>
-        <direction><foo /></direction>
-    </bullet>
+    <fire>
+        <bulletRef label="bar" />
+    </fire>
 </bulletml>"##,
         );
         let err = bml.unwrap_err();
-        let (node_type, pos) = assert_matches!(
+        let (label, kind, pos) = assert_matches!(
             err,
-            ParseError::UnexpectedNodeType {
-                ref node_type,
+            ParseError::UnresolvedReference {
+                ref label,
+                kind,
                 pos,
                 #[cfg(feature = "backtrace")]
                 backtrace: _,
-            } => (node_type, pos)
+            } => (label, kind, pos)
         );
-        assert_eq!(node_type, "Element");
-        assert_eq!((pos.row(), pos.col()), (4, 20));
+        assert_eq!(label, "bar");
+        assert_eq!(kind, ReferenceKind::Bullet);
+        assert_eq!((pos.row(), pos.col()), (4, 9));
         assert_eq!(
             format!("{}", &err),
-            "Unexpected node of type Element at position 4:20"
+            "Unresolved label bar in element bulletRef at position 4:9"
         );
     }
 
     #[test]
-    fn test_expression_error() {
-        use std::error::Error;
-
+    fn test_unresolved_action_ref() {
         let bml = BulletMLParser::new().parse(
             r##"<?xml version="1.0" ?>
 <bulletml>
     <bullet>
-        <direction>-</direction>
+        <actionRef label="bar" />
     </bullet>
 </bulletml>"##,
         );
         let err = bml.unwrap_err();
-        let pos = assert_matches!(
+        let (label, kind, pos) = assert_matches!(
             err,
-            ParseError::Expression {
-                source: _,
+            ParseError::UnresolvedReference {
+                ref label,
+                kind,
                 pos,
                 #[cfg(feature = "backtrace")]
                 backtrace: _,
-            } => pos
+            } => (label, kind, pos)
         );
-        assert_eq!((pos.row(), pos.col()), (4, 20));
-        let cause = err.source().unwrap().downcast_ref::<fasteval::Error>();
-        assert_matches!(
-            cause,
-            Some(&fasteval::Error::EofWhileParsing(ref s)) if s.as_str() == "value"
+        assert_eq!(label, "bar");
+        assert_eq!(kind, ReferenceKind::Action);
+        assert_eq!((pos.row(), pos.col()), (4, 9));
+        assert_eq!(
+            format!("{}", &err),
+            "Unresolved label bar in element actionRef at position 4:9"
+        );
+    }
+
+    #[test]
+    fn test_unresolved_fire_ref() {
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <action>
+        <fireRef label="bar" />
+    </action>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let (label, kind, pos) = assert_matches!(
+            err,
+            ParseError::UnresolvedReference {
+                ref label,
+                kind,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (label, kind, pos)
         );
-        assert_eq!(format!("{}", &err), "Expression error at position 4:20");
+        assert_eq!(label, "bar");
+        assert_eq!(kind, ReferenceKind::Fire);
+        assert_eq!((pos.row(), pos.col()), (4, 9));
+        assert_eq!(
+            format!("{}", &err),
+            "Unresolved label bar in element fireRef at position 4:9"
+        );
+    }
+
+    #[test]
+    fn test_reference_of_wrong_kind() {
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <actionRef label="bar" />
+    </action>
+    <bullet label="bar" />
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        assert_matches!(
+            err,
+            ParseError::UnresolvedReference {
+                ref label,
+                kind: ReferenceKind::Action,
+                ..
+            } if label == "bar"
+        );
+    }
+
+    #[test]
+    fn test_missing_parameters() {
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <actionRef label="bar">
+            <param>1</param>
+        </actionRef>
+    </action>
+    <action label="bar">
+        <wait>$1 + $2</wait>
+    </action>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let (label, kind, expected, found, pos) = assert_matches!(
+            err,
+            ParseError::ParameterCount {
+                ref label,
+                kind,
+                expected,
+                found,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (label, kind, expected, found, pos)
+        );
+        assert_eq!(label, "bar");
+        assert_eq!(kind, ReferenceKind::Action);
+        assert_eq!((expected, found), (2, 1));
+        assert_eq!((pos.row(), pos.col()), (4, 9));
+        assert_eq!(
+            format!("{}", &err),
+            "Label bar in element actionRef expects 2 parameters but 1 are passed at position 4:9"
+        );
+    }
+
+    #[test]
+    fn test_nested_parameters() {
+        // The parameters of the inner reference are evaluated in the context of "bar", the ones
+        // used by "baz" do not count.
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <fire label="top">
+        <bulletRef label="bar" />
+    </fire>
+    <bullet label="bar">
+        <action>
+            <fireRef label="baz">
+                <param>$1</param>
+                <param>$1</param>
+                <param>$1</param>
+            </fireRef>
+        </action>
+    </bullet>
+    <fire label="baz">
+        <direction>$3</direction>
+        <bullet />
+    </fire>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        assert_matches!(
+            err,
+            ParseError::ParameterCount {
+                ref label,
+                kind: ReferenceKind::Bullet,
+                expected: 1,
+                found: 0,
+                ..
+            } if label == "bar"
+        );
+    }
+
+    #[test]
+    fn test_extra_parameters() {
+        let (_, warnings) = BulletMLParser::new()
+            .parse_with_warnings(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <fireRef label="bar">
+            <param>1</param>
+            <param>2</param>
+        </fireRef>
+    </action>
+    <fire label="bar">
+        <speed>$1</speed>
+        <bullet />
+    </fire>
+</bulletml>"##,
+            )
+            .unwrap();
+        assert_eq!(warnings.len(), 1);
+        let (label, kind, expected, found, pos) = assert_matches!(
+            warnings[0],
+            ParseError::ParameterCount {
+                ref label,
+                kind,
+                expected,
+                found,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (label, kind, expected, found, pos)
+        );
+        assert_eq!(label, "bar");
+        assert_eq!(kind, ReferenceKind::Fire);
+        assert_eq!((expected, found), (1, 2));
+        assert_eq!((pos.row(), pos.col()), (4, 9));
+    }
+
+    #[test]
+    fn test_unguarded_recursion() {
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <actionRef label="foo" />
+    </action>
+    <action label="foo">
+        <fire>
+            <bullet />
+        </fire>
+        <repeat>
+            <times>2</times>
+            <actionRef label="bar" />
+        </repeat>
+        <wait>1</wait>
+    </action>
+    <action label="bar">
+        <wait>0</wait>
+        <actionRef label="foo" />
+    </action>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let (label, kind, pos) = assert_matches!(
+            err,
+            ParseError::UnguardedRecursion {
+                ref label,
+                kind,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (label, kind, pos)
+        );
+        assert_eq!(label, "foo");
+        assert_eq!(kind, ReferenceKind::Action);
+        assert_eq!((pos.row(), pos.col()), (18, 9));
+        assert_eq!(
+            format!("{}", &err),
+            "Label foo in element actionRef recurses without waiting at position 18:9"
+        );
+    }
+
+    #[test]
+    fn test_guarded_recursion() {
+        BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <actionRef label="foo" />
+    </action>
+    <action label="foo">
+        <actionRef label="bar" />
+        <actionRef label="foo" />
+    </action>
+    <action label="bar">
+        <action>
+            <wait>1</wait>
+        </action>
+        <actionRef label="foo" />
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_allowed_unguarded_recursion() {
+        let xml = r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <actionRef label="foo">
+            <param>1</param>
+        </actionRef>
+    </action>
+    <action label="foo">
+        <wait>$1</wait>
+        <actionRef label="foo">
+            <param>$1</param>
+        </actionRef>
+    </action>
+</bulletml>"##;
+        assert_matches!(
+            BulletMLParser::new().parse(xml),
+            Err(ParseError::UnguardedRecursion { .. })
+        );
+        BulletMLParser::new()
+            .allow_unguarded_recursion(true)
+            .parse(xml)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let (bml, diagnostics) = BulletMLParser::new()
+            .validate_structure(true)
+            .parse_with_diagnostics(
+                r##"<?xml version="1.0" ?>
+<bulletml type="diagonal">
+    <foo />
+    <action label="top">
+        <fire>
+            <direction type="up">1 +</direction>
+            <bulletRef />
+        </fire>
+        <actionRef label="missing" />
+        <actionRef label="a1">
+            <param>1</param>
+        </actionRef>
+        <wait>10</wait>
+    </action>
+    <action label="a1">
+        <vanish />
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let summary = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let pos = diagnostic.pos().unwrap();
+                (diagnostic.severity(), pos.row(), pos.col())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Severity::Error, 2, 17),
+                (Severity::Error, 3, 5),
+                (Severity::Error, 6, 30),
+                (Severity::Error, 6, 37),
+                (Severity::Error, 7, 13),
+                (Severity::Error, 9, 9),
+                (Severity::Warning, 10, 9),
+            ]
+        );
+        assert_eq!(
+            format!("{}", diagnostics[1]),
+            "error: Unexpected element foo at position 3:5"
+        );
+        assert_eq!(
+            format!("{}", diagnostics[6]),
+            "warning: Label a1 in element actionRef expects 0 parameters but 1 are passed at position 10:9"
+        );
+
+        // The document keeps everything which could be parsed, with defaults for the rest.
+        let top = bml.action_refs["top"];
+        let children = top
+            .children(&bml.arena)
+            .map(|child| bml.arena[child].get())
+            .collect::<Vec<_>>();
+        assert_matches!(
+            &children[..],
+            &[
+                &BulletMLNode::Fire(None),
+                BulletMLNode::ActionRef(label),
+                &BulletMLNode::Wait(BulletMLExpression::Const(_)),
+            ] if label == "a1"
+        );
+        let fire = top.children(&bml.arena).next().unwrap();
+        let direction = fire.children(&bml.arena).next().unwrap();
+        assert_matches!(
+            bml.arena[direction].get(),
+            &BulletMLNode::Direction {
+                dir_type: None,
+                dir: BulletMLExpression::Const(value),
+            } if value == 0.
+        );
+        assert_eq!(fire.children(&bml.arena).count(), 1);
+    }
+
+    #[test]
+    fn test_diagnostics_unguarded_recursion() {
+        let (bml, diagnostics) = BulletMLParser::new()
+            .parse_with_diagnostics(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="a1">
+        <actionRef label="a2" />
+    </action>
+    <action label="a2">
+        <actionRef label="a1" />
+    </action>
+    <action label="a3">
+        <actionRef label="a3" />
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| matches!(diagnostic.error(), ParseError::UnguardedRecursion { .. })));
+        assert!(analysis::find_unguarded_cycle(&bml.arena, &bml.action_refs).is_none());
+    }
+
+    #[test]
+    fn test_diagnostics_fatal_error() {
+        let res = BulletMLParser::new().parse_with_diagnostics(
+            r##"<?xml version="1.0" ?>
+<bulletml></foo>"##,
+        );
+        assert_matches!(res, Err(ParseError::Xml { .. }));
+        assert_eq!(res.unwrap_err().pos().map(|pos| pos.row()), Some(2));
+    }
+
+    #[test]
+    fn test_node_spans() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <wait>1</wait>
+        <fireRef label="f1" />
+    </action>
+    <fire label="f1"><bullet /></fire>
+</bulletml>"##,
+            )
+            .unwrap();
+        let span = |id| {
+            let (start, end) = bml.node_span(id).unwrap();
+            ((start.row(), start.col()), (end.row(), end.col()))
+        };
+        assert_eq!(span(bml.root), ((2, 1), (8, 12)));
+        let top = bml.action_refs["top"];
+        assert_eq!(span(top), ((3, 5), (6, 14)));
+        let mut children = top.children(&bml.arena);
+        assert_eq!(span(children.next().unwrap()), ((4, 9), (4, 23)));
+        assert_eq!(span(children.next().unwrap()), ((5, 9), (5, 31)));
+        let bullet = bml.fire_refs["f1"].children(&bml.arena).next().unwrap();
+        assert_eq!(span(bullet), ((7, 22), (7, 32)));
+        assert_eq!(bml.spans.len(), bml.arena.len());
+    }
+
+    fn resolver(documents: &[(&str, &str)]) -> impl FnMut(&str) -> Result<String, ParseError> {
+        let documents = documents
+            .iter()
+            .map(|(src, text)| (src.to_string(), text.to_string()))
+            .collect::<HashMap<_, _>>();
+        move |src| {
+            documents
+                .get(src)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, src.to_string()).into())
+        }
+    }
+
+    const PATTERNS: &str = r##"<?xml version="1.0" ?>
+<bulletml>
+    <import src="shapes.xml" as="shapes" />
+    <action label="ring">
+        <fireRef label="straight" />
+        <actionRef label="shapes.line" />
+    </action>
+    <fire label="straight">
+        <bullet label="b" />
+    </fire>
+</bulletml>"##;
+
+    const SHAPES: &str = r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="line">
+        <wait>1</wait>
+    </action>
+</bulletml>"##;
+
+    #[test]
+    fn test_import() {
+        let bml = BulletMLParser::new()
+            .import_resolver(resolver(&[
+                ("patterns.xml", PATTERNS),
+                ("shapes.xml", SHAPES),
+            ]))
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <import src="patterns.xml" as="patterns" />
+    <action label="top">
+        <actionRef label="patterns.ring" />
+        <actionRef label="patterns.shapes.line" />
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut labels = bml.action_refs.keys().cloned().collect::<Vec<_>>();
+        labels.sort();
+        assert_eq!(labels, vec!["patterns.ring", "patterns.shapes.line", "top"]);
+        assert!(bml.fire_refs.contains_key("patterns.straight"));
+        assert!(bml.bullet_refs.contains_key("patterns.b"));
+
+        let ring = bml.action_refs["patterns.ring"];
+        let refs = ring
+            .children(&bml.arena)
+            .filter_map(|child| bml.arena[child].get().match_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            refs,
+            vec![
+                (ReferenceKind::Fire, "patterns.straight"),
+                (ReferenceKind::Action, "patterns.shapes.line"),
+            ]
+        );
+        // Imported definitions are flattened into the importing document.
+        assert_eq!(ring.ancestors(&bml.arena).nth(1), Some(bml.root));
+    }
+
+    #[test]
+    fn test_import_own_labels_only() {
+        let bml = BulletMLParser::new()
+            .import_resolver(resolver(&[(
+                "patterns.xml",
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="ring">
+        <actionRef label="top" />
+    </action>
+</bulletml>"##,
+            )]))
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top" />
+    <import src="patterns.xml" as="patterns" />
+</bulletml>"##,
+            );
+        let err = bml.unwrap_err();
+        let (src, pos, source) = assert_matches!(
+            err,
+            ParseError::Import {
+                ref src,
+                pos,
+                ref source,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (src, pos, source)
+        );
+        assert_eq!(src, "patterns.xml");
+        assert_eq!((pos.row(), pos.col()), (4, 5));
+        assert_matches!(
+            **source,
+            ParseError::UnresolvedReference {
+                ref label,
+                pos,
+                ..
+            } if label == "patterns.top" && (pos.row(), pos.col()) == (4, 9)
+        );
+        assert_eq!(
+            format!("{}", &err),
+            "Error in document patterns.xml imported at position 4:5"
+        );
+    }
+
+    #[test]
+    fn test_import_cycle() {
+        let bml = BulletMLParser::new()
+            .import_resolver(resolver(&[
+                (
+                    "a.xml",
+                    r##"<bulletml><import src="b.xml" as="b" /></bulletml>"##,
+                ),
+                (
+                    "b.xml",
+                    r##"<bulletml><import src="a.xml" as="a" /></bulletml>"##,
+                ),
+            ]))
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <import src="a.xml" as="a" />
+</bulletml>"##,
+            );
+        let err = bml.unwrap_err();
+        let source = assert_matches!(err, ParseError::Import { ref src, ref source, .. } if src == "a.xml" => source);
+        let source = assert_matches!(**source, ParseError::Import { ref src, ref source, .. } if src == "b.xml" => source);
+        assert_matches!(
+            **source,
+            ParseError::ImportCycle {
+                ref src,
+                pos,
+                ..
+            } if src == "a.xml" && (pos.row(), pos.col()) == (1, 11)
+        );
+
+        // Cycles are found on the resolved paths.
+        let bml = BulletMLParser::new()
+            .import_resolver(resolver(&[(
+                "sub/a.xml",
+                r##"<bulletml><import src="../sub/./a.xml" as="a" /></bulletml>"##,
+            )]))
+            .parse(r##"<bulletml><import src="sub/a.xml" as="a" /></bulletml>"##);
+        let err = bml.unwrap_err();
+        let source = assert_matches!(err, ParseError::Import { ref src, ref source, .. } if src == "sub/a.xml" => source);
+        assert_matches!(
+            **source,
+            ParseError::ImportCycle { ref src, .. } if src == "sub/a.xml"
+        );
+    }
+
+    #[test]
+    fn test_import_without_resolver() {
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <import src="patterns.xml" as="patterns" />
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let source = assert_matches!(err, ParseError::Import { ref source, .. } => source);
+        assert_matches!(**source, ParseError::Io { .. });
+    }
+
+    #[test]
+    fn test_import_diagnostics() {
+        let (bml, diagnostics) = BulletMLParser::new()
+            .import_resolver(resolver(&[(
+                "patterns.xml",
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="ring">
+        <foo />
+        <wait>1</wait>
+    </action>
+</bulletml>"##,
+            )]))
+            .parse_with_diagnostics(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <import src="patterns.xml" as="patterns" />
+    <import src="missing.xml" as="missing" />
+    <import src="patterns.xml" />
+</bulletml>"##,
+            )
+            .unwrap();
+        assert!(bml.action_refs.contains_key("patterns.ring"));
+        assert_eq!(diagnostics.len(), 3);
+        assert_matches!(
+            diagnostics[0].error(),
+            ParseError::Import { src, source, .. }
+                if src == "patterns.xml" && matches!(**source, ParseError::UnexpectedElement { .. })
+        );
+        assert_matches!(
+            diagnostics[1].error(),
+            ParseError::Import { src, source, .. }
+                if src == "missing.xml" && matches!(**source, ParseError::Io { .. })
+        );
+        assert_matches!(
+            diagnostics[2].error(),
+            ParseError::MissingAttribute { attribute, .. } if attribute == "as"
+        );
+    }
+
+    #[test]
+    fn test_import_file() {
+        let dir = std::env::temp_dir().join(format!("bulletml-import-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("shapes.xml"), SHAPES).unwrap();
+        fs::write(
+            dir.join("main.xml"),
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <import src="shapes.xml" as="shapes" />
+    <action label="top">
+        <actionRef label="shapes.line" />
+    </action>
+</bulletml>"##,
+        )
+        .unwrap();
+        let bml = BulletMLParser::new().parse_file(dir.join("main.xml"));
+
+        // Nested imports are relative to the importing document.
+        fs::remove_file(dir.join("shapes.xml")).unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub").join("patterns.xml"), PATTERNS).unwrap();
+        fs::write(dir.join("sub").join("shapes.xml"), SHAPES).unwrap();
+        fs::write(
+            dir.join("nested.xml"),
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <import src="sub/patterns.xml" as="patterns" />
+    <import src="sub/shapes.xml" as="shapes" />
+</bulletml>"##,
+        )
+        .unwrap();
+        let nested = BulletMLParser::new().parse_file(dir.join("nested.xml"));
+
+        // Imports cannot leave the directory of the importing document without custom resolver.
+        let outside = dir.join("sub").join("outside.xml");
+        let escaping = [
+            "../main.xml".to_string(),
+            "patterns.xml/../../main.xml".to_string(),
+            dir.join("main.xml").to_string_lossy().into_owned(),
+        ];
+        let escapes = escaping
+            .iter()
+            .map(|src| {
+                fs::write(
+                    &outside,
+                    format!(r#"<bulletml><import src="{}" as="a" /></bulletml>"#, src),
+                )
+                .unwrap();
+                BulletMLParser::new().parse_file(&outside)
+            })
+            .collect::<Vec<_>>();
+        let resolved = BulletMLParser::new()
+            .import_resolver(|_| Ok(SHAPES.to_string()))
+            .parse_file(&outside);
+        fs::remove_dir_all(&dir).unwrap();
+        for (src, escape) in escaping.iter().zip(escapes) {
+            assert_matches!(
+                escape,
+                Err(ParseError::Import { src: ref import, ref source, .. })
+                    if import == src
+                        && matches!(**source, ParseError::Io { ref source, .. }
+                            if source.kind() == io::ErrorKind::PermissionDenied)
+            );
+        }
+        resolved.unwrap();
+
+        let bml = bml.unwrap();
+        assert!(bml.action_refs.contains_key("shapes.line"));
+        let nested = nested.unwrap();
+        let mut labels = nested.action_refs.keys().cloned().collect::<Vec<_>>();
+        labels.sort();
+        assert_eq!(
+            labels,
+            vec!["patterns.ring", "patterns.shapes.line", "shapes.line"]
+        );
+    }
+
+    #[test]
+    fn test_is_confined() {
+        assert!(BulletMLParser::is_confined("a.xml"));
+        assert!(BulletMLParser::is_confined("./sub/a.xml"));
+        assert!(BulletMLParser::is_confined("sub/../a.xml"));
+        assert!(!BulletMLParser::is_confined("../a.xml"));
+        assert!(!BulletMLParser::is_confined("sub/../../a.xml"));
+        assert!(!BulletMLParser::is_confined("/a.xml"));
+    }
+
+    #[test]
+    fn test_import_path() {
+        let import_path = BulletMLParser::import_path;
+        assert_eq!(import_path(None, "a.xml"), "a.xml");
+        assert_eq!(import_path(None, "./sub//a.xml"), "sub/a.xml");
+        assert_eq!(import_path(Some("a.xml"), "b.xml"), "b.xml");
+        assert_eq!(import_path(Some("sub/a.xml"), "b.xml"), "sub/b.xml");
+        assert_eq!(import_path(Some("sub/a.xml"), "../b.xml"), "b.xml");
+        assert_eq!(import_path(Some("sub/a.xml"), "../../b.xml"), "../b.xml");
+        assert_eq!(import_path(Some("../a.xml"), "../b.xml"), "../../b.xml");
+        assert_eq!(import_path(Some("/sub/a.xml"), "../../b.xml"), "/b.xml");
+        assert_eq!(import_path(Some("sub/a.xml"), "/b.xml"), "/b.xml");
+    }
+
+    #[test]
+    fn test_valid_structure() {
+        BulletMLParser::new()
+            .validate_structure(true)
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <bullet label="b1">
+        <direction type="aim">0</direction>
+        <speed>1</speed>
+        <action>
+            <changeSpeed>
+                <speed>0</speed>
+                <term>10</term>
+            </changeSpeed>
+        </action>
+        <actionRef label="a1" />
+    </bullet>
+    <action label="a1">
+        <repeat>
+            <times>3</times>
+            <action>
+                <fire>
+                    <speed>2</speed>
+                    <bulletRef label="b1" />
+                </fire>
+                <wait>1</wait>
+            </action>
+        </repeat>
+        <accel>
+            <vertical>1</vertical>
+            <term>5</term>
+        </accel>
+        <vanish />
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_missing_element() {
+        let bml = BulletMLParser::new().validate_structure(true).parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <fire>
+        <direction>0</direction>
+    </fire>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let (expected, element, pos) = assert_matches!(
+            err,
+            ParseError::MissingElement {
+                ref expected,
+                ref element,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (expected, element, pos)
+        );
+        assert_eq!(expected, "bullet | bulletRef");
+        assert_eq!(element, "fire");
+        assert_eq!((pos.row(), pos.col()), (3, 5));
+        assert_eq!(
+            format!("{}", &err),
+            "Missing element bullet | bulletRef in element fire at position 3:5"
+        );
+    }
+
+    #[test]
+    fn test_missing_element_is_ignored_without_validation() {
+        BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action>
+        <repeat>
+            <action />
+        </repeat>
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let bml = BulletMLParser::new().validate_structure(true).parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <action>
+        <repeat>
+            <action />
+        </repeat>
+    </action>
+</bulletml>"##,
+        );
+        assert_matches!(
+            bml,
+            Err(ParseError::MissingElement {
+                ref expected,
+                ref element,
+                ..
+            }) if expected == "times" && element == "repeat"
+        );
+    }
+
+    #[test]
+    fn test_misplaced_element() {
+        let bml = BulletMLParser::new().validate_structure(true).parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <fire>
+        <speed>1</speed>
+        <direction>0</direction>
+        <bullet />
+    </fire>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let (child, element, pos) = assert_matches!(
+            err,
+            ParseError::MisplacedElement {
+                ref child,
+                ref element,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (child, element, pos)
+        );
+        assert_eq!(child, "direction");
+        assert_eq!(element, "fire");
+        assert_eq!((pos.row(), pos.col()), (5, 9));
+        assert_eq!(
+            format!("{}", &err),
+            "Misplaced element direction in element fire at position 5:9"
+        );
+    }
+
+    #[test]
+    fn test_repeated_element() {
+        let bml = BulletMLParser::new().validate_structure(true).parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <fire>
+        <speed>1</speed>
+        <speed>2</speed>
+        <bullet />
+    </fire>
+</bulletml>"##,
+        );
+        assert_matches!(
+            bml,
+            Err(ParseError::MisplacedElement {
+                ref child,
+                ref element,
+                pos,
+                ..
+            }) if child == "speed" && element == "fire" && (pos.row(), pos.col()) == (5, 9)
+        );
+    }
+
+    #[test]
+    fn test_element_in_text_element() {
+        let bml = BulletMLParser::new().validate_structure(true).parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <action>
+        <vanish><wait>1</wait></vanish>
+    </action>
+</bulletml>"##,
+        );
+        assert_matches!(
+            bml,
+            Err(ParseError::UnexpectedElement {
+                ref element,
+                pos,
+                ..
+            }) if element == "wait" && (pos.row(), pos.col()) == (4, 17)
+        );
+    }
+
+    #[test]
+    fn test_unexpected_node_type_in_expression() {
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <bullet>
+        <direction><foo /></direction>
+    </bullet>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let (node_type, pos) = assert_matches!(
+            err,
+            ParseError::UnexpectedNodeType {
+                ref node_type,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (node_type, pos)
+        );
+        assert_eq!(node_type, "Element");
+        assert_eq!((pos.row(), pos.col()), (4, 20));
+        assert_eq!(
+            format!("{}", &err),
+            "Unexpected node of type Element at position 4:20"
+        );
+    }
+
+    #[test]
+    fn test_expression_error() {
+        use std::error::Error;
+
+        let bml = BulletMLParser::new().parse(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+    <bullet>
+        <direction>-</direction>
+    </bullet>
+</bulletml>"##,
+        );
+        let err = bml.unwrap_err();
+        let (expression, pos) = assert_matches!(
+            err,
+            ParseError::Expression {
+                source: _,
+                ref expression,
+                pos,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            } => (expression, pos)
+        );
+        assert_eq!(expression, "-");
+        assert_eq!((pos.row(), pos.col()), (4, 21));
+        let cause = err.source().unwrap().downcast_ref::<ExpressionError>();
+        assert_matches!(
+            cause,
+            Some(ExpressionError::UnexpectedEnd { offset: 1, .. })
+        );
+        assert_eq!(
+            format!("{}", &err),
+            r#"Expression error in "-" at position 4:21"#
+        );
+
+        let err = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <wait>
+            $rand * <!-- comment --> 3
+            + * 1
+        </wait>
+    </action>
+</bulletml>"##,
+            )
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", &err),
+            "Expression error in \"$rand *  3\\n            + * 1\" at position 6:15"
+        );
+        assert_eq!(
+            format!("{}", err.source().unwrap()),
+            "Unexpected character '*' at offset 25"
+        );
+    }
+
+    #[test]
+    fn test_undefined_names() {
+        use std::error::Error;
+
+        let parse = |parser: BulletMLParser, expression: &str| {
+            parser.parse(&format!(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <wait>{}</wait>
+    </action>
+</bulletml>"##,
+                expression
+            ))
+        };
+        let undefined = |err: ParseError| match err.source().unwrap().downcast_ref() {
+            Some(ExpressionError::UndefinedVariable { name, .. })
+            | Some(ExpressionError::UndefinedFunction { name, .. }) => name.clone(),
+            cause => panic!("Unexpected cause {:?}", cause),
+        };
+
+        let err = parse(BulletMLParser::new(), "$hp + 1").unwrap_err();
+        assert_eq!(undefined(err), "$hp");
+        let err = parse(BulletMLParser::new(), "angleTo(1, 2)").unwrap_err();
+        assert_eq!(undefined(err), "angleTo");
+        let err = parse(BulletMLParser::new().variable("hp"), "$hp * $loopCount").unwrap_err();
+        assert_eq!(undefined(err), "$loopCount");
+
+        assert!(parse(
+            BulletMLParser::new().variable("hp").function("angleTo"),
+            "angleTo($hp, 2) + min($rank, $loop.index)",
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_expression_source() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <wait>
+            $rand * <!-- comment --> 3
+            + 1
+        </wait>
+        <wait> 2 </wait>
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut waits = bml.arena.iter().filter_map(|node| match node.get() {
+            BulletMLNode::Wait(expr) => Some(expr),
+            _ => None,
+        });
+        let expr = waits.next().unwrap();
+        let source = expr.source().unwrap();
+        assert_eq!(source.text(), "$rand *  3\n            + 1");
+        let (start, end) = source.span().unwrap();
+        assert_eq!((start.row(), start.col()), (5, 13));
+        assert_eq!((end.row(), end.col()), (6, 16));
+        assert_eq!(
+            format!("{:?}", expr),
+            r#"Expr("$rand *  3\n            + 1")"#
+        );
+        let expr = waits.next().unwrap();
+        assert_matches!(expr, BulletMLExpression::Const(value) if *value == 2.);
+        assert!(expr.source().is_none());
+        assert_eq!(format!("{:?}", expr), "Const(2.0)");
+    }
+
+    const JAPANESE_DOCUMENT: &str = r##"<?xml version="1.0" encoding="ENCODING" ?>
+<bulletml>
+    <action label="弾幕">
+        <wait>1</wait>
+    </action>
+</bulletml>"##;
+
+    fn encode_japanese_document(encoding: &'static Encoding) -> Vec<u8> {
+        let xml = JAPANESE_DOCUMENT.replace("ENCODING", encoding.name());
+        let (bytes, _, unmappable) = encoding.encode(&xml);
+        assert!(!unmappable);
+        bytes.into_owned()
+    }
+
+    #[test]
+    fn test_parse_bytes_shift_jis() {
+        let bytes = encode_japanese_document(encoding_rs::SHIFT_JIS);
+        assert!(std::str::from_utf8(&bytes).is_err());
+        let bml = BulletMLParser::new().parse_bytes(&bytes).unwrap();
+        assert!(bml.action_refs.contains_key("弾幕"));
+    }
+
+    #[test]
+    fn test_parse_reader_euc_jp() {
+        let bytes = encode_japanese_document(encoding_rs::EUC_JP);
+        let bml = BulletMLParser::new().parse_reader(&bytes[..]).unwrap();
+        assert!(bml.action_refs.contains_key("弾幕"));
+    }
+
+    #[test]
+    fn test_parse_bytes_utf16_bom() {
+        let xml = JAPANESE_DOCUMENT.replace("ENCODING", "UTF-16");
+        let mut bytes = vec![0xff, 0xfe];
+        for unit in xml.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        let bml = BulletMLParser::new().parse_bytes(&bytes).unwrap();
+        assert!(bml.action_refs.contains_key("弾幕"));
+    }
+
+    #[test]
+    fn test_parse_bytes_invalid_utf8() {
+        let mut bytes = br##"<?xml version="1.0" ?>
+<bulletml>"##
+            .to_vec();
+        let offset = bytes.len();
+        bytes.extend_from_slice(b"\xff</bulletml>");
+        let bml = BulletMLParser::new().parse_bytes(&bytes);
+        assert_matches!(
+            bml,
+            Err(ParseError::Decoding {
+                ref encoding,
+                offset: o,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            }) if encoding == "UTF-8" && o == offset
+        );
+    }
+
+    #[test]
+    fn test_parse_bytes_unknown_encoding() {
+        let bml = BulletMLParser::new().parse_bytes(
+            br##"<?xml version="1.0" encoding='klingon' ?>
+<bulletml />"##,
+        );
+        assert_matches!(
+            bml,
+            Err(ParseError::UnknownEncoding {
+                ref encoding,
+                #[cfg(feature = "backtrace")]
+                backtrace: _,
+            }) if encoding == "klingon"
+        );
+    }
+
+    #[test]
+    fn test_expression_error_after_entities() {
+        // The entity shifts the offsets of the text from the ones in the document, they must not
+        // be used to index it.
+        let res = BulletMLParser::new().parse(
+            "<bulletml><action label=\"top\"><wait>&#x20;\u{3000}\u{3000}x</wait></action></bulletml>",
+        );
+        assert_matches!(
+            res,
+            Err(ParseError::Expression {
+                source: ExpressionError::UndefinedVariable { .. },
+                pos,
+                ..
+            }) if pos.row() == 1 && pos.col() == 40
+        );
+    }
+
+    #[test]
+    fn test_nesting_too_deep() {
+        let depth = 1_000;
+        let doc = format!(
+            "<bulletml>{}{}</bulletml>",
+            "<action>".repeat(depth),
+            "</action>".repeat(depth)
+        );
+        let res = BulletMLParser::new().parse(&doc);
+        assert_matches!(
+            res,
+            Err(ParseError::NestingTooDeep { ref element, pos, .. })
+                if element == "action" && pos.col() == 11 + 8 * MAX_NESTING as u32
+        );
+
+        let doc = format!(
+            "<bulletml>{}{}</bulletml>",
+            "<action><repeat><times>1</times><action><fire><bullet>".repeat(MAX_NESTING / 2),
+            "</bullet></fire></action></repeat></action>".repeat(MAX_NESTING / 2)
+        );
+        BulletMLParser::new().parse(&doc).unwrap();
+
+        let mut err = BulletMLParser::new()
+            .import_resolver(|src| {
+                Ok(format!(
+                    r#"<bulletml><import src="{}0" as="a" /></bulletml>"#,
+                    src
+                ))
+            })
+            .parse(r#"<bulletml><import src="0" as="a" /></bulletml>"#)
+            .unwrap_err();
+        let mut imports = 0;
+        while let ParseError::Import { source, .. } = err {
+            imports += 1;
+            err = *source;
+        }
+        assert_eq!(imports, MAX_NESTING);
+        assert_matches!(err, ParseError::NestingTooDeep { ref element, .. } if element == "import");
+    }
+
+    const TOKENS: &[&str] = &[
+        "$1",
+        "$2",
+        "$rank",
+        "$rand",
+        "$loop.index",
+        "$loop.1.index",
+        "$300",
+        "$99999999999999999999",
+        "$",
+        "1",
+        "0.5",
+        "1e400",
+        ".",
+        "e",
+        "+",
+        "-",
+        "*",
+        "/",
+        "%",
+        "^",
+        "(",
+        ")",
+        ",",
+        "=",
+        "&lt;",
+        "&gt;=",
+        "&amp;",
+        "&#x20;",
+        "&#233;",
+        "\u{3000}",
+        "é",
+        " ",
+        "\n",
+        "sin(",
+        "max(",
+        "rank",
+        "<!-- comment -->",
+        "<![CDATA[ 2 * ]]>",
+        "<param>1</param>",
+    ];
+
+    const ELEMENTS: &[&str] = &[
+        "bulletml",
+        "bullet",
+        "action",
+        "fire",
+        "repeat",
+        "changeDirection",
+        "changeSpeed",
+        "accel",
+        "bulletRef",
+        "actionRef",
+        "fireRef",
+        "import",
+        "custom",
+        "unknown",
+    ];
+
+    const LEAF_ELEMENTS: &[&str] = &[
+        "wait",
+        "vanish",
+        "direction",
+        "speed",
+        "horizontal",
+        "vertical",
+        "term",
+        "times",
+        "param",
+        "custom",
+    ];
+
+    const ATTRIBUTES: &[&str] = &[
+        "",
+        r#" label="top""#,
+        r#" label="a""#,
+        r#" label="b""#,
+        r#" label="a" label="b""#,
+        r#" type="aim""#,
+        r#" type="sequence""#,
+        r#" type="vertical""#,
+        r#" type="unknown""#,
+        r#" src="a.xml" as="a""#,
+        r#" src="b.xml""#,
+    ];
+
+    fn expression() -> impl Strategy<Value = String> {
+        proptest::collection::vec(select(TOKENS), 0..12).prop_map(|tokens| tokens.concat())
+    }
+
+    fn element() -> impl Strategy<Value = String> {
+        let leaf = (select(LEAF_ELEMENTS), select(ATTRIBUTES), expression()).prop_map(
+            |(name, attributes, expr)| format!("<{0}{1}>{2}</{0}>", name, attributes, expr),
+        );
+        leaf.prop_recursive(6, 64, 5, |element| {
+            (
+                select(ELEMENTS),
+                select(ATTRIBUTES),
+                proptest::collection::vec(element, 0..5),
+            )
+                .prop_map(|(name, attributes, children)| {
+                    format!("<{0}{1}>{2}</{0}>", name, attributes, children.concat())
+                })
+        })
+    }
+
+    fn document() -> impl Strategy<Value = String> {
+        (
+            select(ATTRIBUTES),
+            proptest::collection::vec(element(), 0..5),
+        )
+            .prop_map(|(attributes, children)| {
+                format!("<bulletml{}>{}</bulletml>", attributes, children.concat())
+            })
+    }
+
+    /// Parses `doc` with all the options, expecting errors but no panic.
+    fn parse_all_ways(doc: &str) {
+        let _ = BulletMLParser::new().parse(doc);
+        let imported = doc.to_string();
+        let _ = BulletMLParser::new()
+            .validate_structure(true)
+            .allow_unguarded_recursion(true)
+            .custom_element("custom")
+            .variable("hp")
+            .function("angle")
+            .import_resolver(move |_| Ok(imported.clone()))
+            .parse_with_diagnostics(doc);
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_never_panics(doc in document()) {
+            parse_all_ways(&doc);
+        }
+
+        #[test]
+        fn test_parse_altered_never_panics(
+            doc in document(),
+            position in any::<proptest::sample::Index>(),
+            insert in "\\PC{0,8}",
+            remove in 0..8usize,
+        ) {
+            let mut position = position.index(doc.len() + 1);
+            while !doc.is_char_boundary(position) {
+                position -= 1;
+            }
+            let end = doc[position..]
+                .char_indices()
+                .nth(remove)
+                .map_or(doc.len(), |(offset, _)| position + offset);
+            let doc = format!("{}{}{}", &doc[..position], insert, &doc[end..]);
+            parse_all_ways(&doc);
+        }
+
+        #[test]
+        fn test_parse_text_never_panics(doc in "\\PC*") {
+            parse_all_ways(&doc);
+        }
+
+        #[test]
+        fn test_parse_bytes_never_panics(
+            prolog in select(&[
+                "",
+                "\u{feff}",
+                "<?xml version=\"1.0\" encoding=\"Shift_JIS\" ?>",
+                "<?xml version=\"1.0\" encoding='UTF-16' ?>",
+                "<?xml version=\"1.0\" encoding=\"klingon\" ?>",
+                "<?xml encoding=",
+            ][..]),
+            bytes in proptest::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let mut doc = prolog.as_bytes().to_vec();
+            doc.extend(bytes);
+            let _ = BulletMLParser::new().parse_bytes(&doc);
+        }
     }
 }
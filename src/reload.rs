@@ -0,0 +1,234 @@
+use crate::errors::{MigrationError, ParseError, ReferenceKind};
+use crate::parse::BulletMLParser;
+use crate::runner::Runner;
+use crate::tree::{BulletML, BulletMLNode};
+use indextree::NodeId;
+use std::fmt::{Display, Formatter};
+use std::mem::discriminant;
+use std::path::Path;
+
+impl BulletML {
+    /// Parses the file at `path` again with `parser`, e.g. after it has been edited, and migrates
+    /// `runners` from this document to the new one with
+    /// [Runner::migrate](struct.Runner.html#method.migrate).
+    ///
+    /// Returns the new document along with the index and the error of each runner which could
+    /// not be migrated. Those runners are ended and the application should remove their bullets.
+    ///
+    /// If the file cannot be parsed, the runners are left untouched and keep running with this
+    /// document.
+    pub fn reload_file<'r, P, R, I>(
+        &self,
+        parser: BulletMLParser,
+        path: P,
+        runners: I,
+    ) -> Result<(BulletML, Vec<(usize, MigrationError)>), ParseError>
+    where
+        P: AsRef<Path>,
+        R: 'r,
+        I: IntoIterator<Item = &'r mut Runner<R>>,
+    {
+        let bml = parser.parse_file(path)?;
+        let failures = runners
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, runner)| runner.migrate(self, &bml).err().map(|err| (index, err)))
+            .collect();
+        Ok((bml, failures))
+    }
+}
+
+/// Location of a node which survives the reloading of a document: the nearest labelled ancestor
+/// of the node, or the root if there is none, and the child indices from there to the node.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NodePath {
+    anchor: Option<(ReferenceKind, String)>,
+    indices: Vec<usize>,
+}
+
+impl NodePath {
+    /// Computes the path of `id` in `bml`, or `None` if the node is not part of the document.
+    pub(crate) fn of(bml: &BulletML, id: NodeId) -> Option<Self> {
+        let mut indices = Vec::new();
+        let mut current = id;
+        let anchor = loop {
+            if let Some((kind, label)) = definition_label(bml.arena[current].get()) {
+                break Some((kind, label.to_string()));
+            }
+            match bml.arena[current].parent() {
+                Some(parent) => {
+                    indices.push(current.preceding_siblings(&bml.arena).count() - 1);
+                    current = parent;
+                }
+                None if current == bml.root => break None,
+                None => return None,
+            }
+        };
+        indices.reverse();
+        Some(NodePath { anchor, indices })
+    }
+
+    /// Finds the node at this path in `bml`, if any.
+    pub(crate) fn resolve(&self, bml: &BulletML) -> Option<NodeId> {
+        let mut current = match &self.anchor {
+            Some((kind, label)) => *match kind {
+                ReferenceKind::Bullet => &bml.bullet_refs,
+                ReferenceKind::Action => &bml.action_refs,
+                ReferenceKind::Fire => &bml.fire_refs,
+            }
+            .get(label)?,
+            None => bml.root,
+        };
+        for index in &self.indices {
+            current = current.children(&bml.arena).nth(*index)?;
+        }
+        Some(current)
+    }
+}
+
+impl Display for NodePath {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match &self.anchor {
+            Some((ReferenceKind::Bullet, label)) => write!(f, "bullet({})", label)?,
+            Some((ReferenceKind::Action, label)) => write!(f, "action({})", label)?,
+            Some((ReferenceKind::Fire, label)) => write!(f, "fire({})", label)?,
+            None => f.write_str("bulletml")?,
+        }
+        for index in &self.indices {
+            write!(f, "/{}", index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the node of `new` at the same path as `id` in `old`. Both nodes must be of the same
+/// kind.
+pub(crate) fn migrate_node(
+    old: &BulletML,
+    new: &BulletML,
+    id: NodeId,
+) -> Result<NodeId, MigrationError> {
+    let path = NodePath::of(old, id)
+        .ok_or_else(|| MigrationError::new_missing_node(format!("{:?}", id)))?;
+    path.resolve(new)
+        .filter(|new_id| {
+            discriminant(new.arena[*new_id].get()) == discriminant(old.arena[id].get())
+        })
+        .ok_or_else(|| MigrationError::new_missing_node(path.to_string()))
+}
+
+fn definition_label(node: &BulletMLNode) -> Option<(ReferenceKind, &str)> {
+    match node {
+        BulletMLNode::Bullet(Some(label)) => Some((ReferenceKind::Bullet, label)),
+        BulletMLNode::Action(Some(label)) => Some((ReferenceKind::Action, label)),
+        BulletMLNode::Fire(Some(label)) => Some((ReferenceKind::Fire, label)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const V1: &str = r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top1">
+        <fire>
+            <bullet />
+        </fire>
+        <wait>10</wait>
+    </action>
+    <action label="top2">
+        <wait>10</wait>
+    </action>
+</bulletml>"##;
+
+    const V2: &str = r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top1">
+        <fire>
+            <speed>2</speed>
+            <bullet />
+        </fire>
+        <wait>20</wait>
+    </action>
+    <action label="top3">
+        <wait>10</wait>
+    </action>
+</bulletml>"##;
+
+    #[test]
+    fn test_node_path() {
+        let v1 = BulletMLParser::new().parse(V1).unwrap();
+        let v2 = BulletMLParser::new().parse(V2).unwrap();
+
+        let top1 = v1.action_refs["top1"];
+        let wait = top1.children(&v1.arena).nth(1).unwrap();
+        let path = NodePath::of(&v1, wait).unwrap();
+        assert_eq!(path.to_string(), "action(top1)/1");
+        let new_wait = path.resolve(&v2).unwrap();
+        assert_eq!(
+            format!("{:?}", v2.arena[new_wait].get()),
+            "Wait(Const(20.0))"
+        );
+        assert_eq!(migrate_node(&v1, &v2, wait).unwrap(), new_wait);
+
+        let path = NodePath::of(&v1, v1.root).unwrap();
+        assert_eq!(path.to_string(), "bulletml");
+        assert_eq!(path.resolve(&v2), Some(v2.root));
+
+        // Same path but another kind of node.
+        let bullet = top1
+            .children(&v1.arena)
+            .next()
+            .unwrap()
+            .children(&v1.arena)
+            .next()
+            .unwrap();
+        let err = migrate_node(&v1, &v2, bullet).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "Node action(top1)/0/0 has no counterpart in the new document"
+        );
+
+        let top2 = v1.action_refs["top2"];
+        assert_matches!(
+            migrate_node(&v1, &v2, top2),
+            Err(MigrationError::MissingNode { ref path, .. }) if path == "action(top2)"
+        );
+    }
+
+    #[test]
+    fn test_reload_file() {
+        let path = std::env::temp_dir().join(format!("bulletml-reload-{}.xml", std::process::id()));
+        fs::write(&path, V1).unwrap();
+        let v1 = BulletMLParser::new().parse_file(&path).unwrap();
+        let mut runners = vec![Runner::new((), &v1)];
+
+        fs::write(&path, "<bulletml></foo>").unwrap();
+        let res = v1.reload_file(BulletMLParser::new(), &path, &mut runners);
+        assert_matches!(res, Err(ParseError::Xml { .. }));
+        assert!(!runners[0].is_end());
+
+        fs::write(&path, V2).unwrap();
+        let (v2, failures) = v1
+            .reload_file(BulletMLParser::new(), &path, &mut runners)
+            .unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_matches!(
+            failures[0],
+            (0, MigrationError::MissingNode { ref path, .. }) if path == "action(top2)"
+        );
+        assert!(runners[0].is_end());
+
+        let mut runners = vec![Runner::new((), &v2)];
+        let (_, failures) = v2
+            .reload_file(BulletMLParser::new(), &path, &mut runners)
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(failures.len(), 0);
+        assert!(!runners[0].is_end());
+    }
+}
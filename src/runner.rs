@@ -1,7 +1,11 @@
 use indextree::{Arena, Node, NodeId};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::ops::{Deref, DerefMut};
 
+use crate::analysis;
+use crate::errors::{MigrationError, ReferenceKind, RunError};
+use crate::expr::Context;
+use crate::reload;
 use crate::tree::{
     BulletML, BulletMLExpression, BulletMLNode, BulletMLType, DirectionType, HVType, SpeedType,
 };
@@ -21,12 +25,56 @@ type Parameters = Vec<f64>;
 /// [Runner::init_from_state](struct.Runner.html#method.init_from_state) when creating new bullets.
 ///
 /// See also [AppRunner::create_bullet](trait.AppRunner.html#tymethod.create_bullet).
+///
+/// With the `serde` feature, a state can be serialized, e.g. to be sent over the network, and
+/// deserialized to be used with the same document.
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct State {
     bml_type: Option<BulletMLType>,
     nodes: Box<[NodeId]>,
     parameters: Parameters,
 }
 
+impl State {
+    fn for_action(bml: &BulletML, label: &str, parameters: Parameters) -> Result<Self, RunError> {
+        let action = *bml
+            .action_refs
+            .get(label)
+            .ok_or_else(|| RunError::new_unknown_action(label.to_string()))?;
+        let expected = analysis::max_parameter(&bml.arena, action);
+        if parameters.len() < expected {
+            return Err(RunError::new_parameter_count(
+                label.to_string(),
+                expected,
+                parameters.len(),
+            ));
+        }
+        Ok(State {
+            bml_type: bml.get_type(),
+            nodes: Box::new([action]),
+            parameters,
+        })
+    }
+
+    /// Moves this state from `old` to `new`, a reloaded version of the same document. It works
+    /// the same way as [Runner::migrate](struct.Runner.html#method.migrate) except that the state
+    /// is left untouched on error.
+    pub fn migrate(&mut self, old: &BulletML, new: &BulletML) -> Result<(), MigrationError> {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| reload::migrate_node(old, new, *node))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.bml_type = new.get_type();
+        self.nodes = nodes.into_boxed_slice();
+        Ok(())
+    }
+}
+
 /// Elementary bullet runner. It is used either to run one single bullet or to run one or more "top"
 /// actions.
 pub struct Runner<R> {
@@ -49,14 +97,7 @@ impl<R> Runner<R> {
                 let child_node = &bml.arena[*child];
                 child_node.get().is_top_action()
             })
-            .map(|action| {
-                let state = State {
-                    bml_type,
-                    nodes: Box::new([action]),
-                    parameters: Vec::new(),
-                };
-                RunnerImpl::new(state)
-            })
+            .map(|action| RunnerImpl::new_top(bml, bml_type, action))
             .collect();
         Runner {
             runners,
@@ -80,16 +121,53 @@ impl<R> Runner<R> {
             let child_node = &bml.arena[*child];
             child_node.get().is_top_action()
         }) {
-            let state = State {
-                bml_type,
-                nodes: Box::new([action]),
-                parameters: Vec::new(),
-            };
-            self.runners.push(RunnerImpl::new(state))
+            self.runners
+                .push(RunnerImpl::new_top(bml, bml_type, action))
         }
         self.app_runner.init();
     }
 
+    /// Creates a new runner for the action labelled `label` of the provided BulletML document,
+    /// whatever its label. That allows to use a document as a library of patterns.
+    ///
+    /// `app_runner` is the application runner which contains all the specific behaviours.
+    ///
+    /// `bml` is the parsed BulletML document to be used by the runner until the bullet dies.
+    ///
+    /// `params` are the values of `$1`, `$2`, ... in the action. An error is returned if the
+    /// label is unknown or if the action uses more parameters.
+    pub fn new_for_action(
+        app_runner: R,
+        bml: &BulletML,
+        label: &str,
+        params: Vec<f64>,
+    ) -> Result<Self, RunError> {
+        let runner = RunnerImpl::new(State::for_action(bml, label, params)?);
+        Ok(Runner {
+            runners: vec![runner.with_label(label)],
+            app_runner,
+        })
+    }
+
+    /// Reuses this runner for the action labelled `label` of the provided BulletML document. It
+    /// works the same way as [new_for_action](#method.new_for_action) and leaves the runner
+    /// untouched on error.
+    pub fn init_for_action<D>(
+        &mut self,
+        bml: &BulletML,
+        label: &str,
+        params: Vec<f64>,
+    ) -> Result<(), RunError>
+    where
+        R: AppRunner<D>,
+    {
+        let runner = RunnerImpl::new(State::for_action(bml, label, params)?);
+        self.runners.clear();
+        self.runners.push(runner.with_label(label));
+        self.app_runner.init();
+        Ok(())
+    }
+
     /// Creates a new runner from an existing state.
     ///
     /// `app_runner` is the application runner which contains all the specific behaviours.
@@ -103,6 +181,14 @@ impl<R> Runner<R> {
         }
     }
 
+    /// Creates a runner which runs no action, e.g. for a bullet which only moves.
+    pub(crate) fn idle(app_runner: R) -> Self {
+        Runner {
+            runners: Vec::new(),
+            app_runner,
+        }
+    }
+
     /// Reuses this runner from an existing state.  It works
     /// the same way as [new_from_state](#method.new_from_state) except that the application
     /// runner cannot change.
@@ -121,23 +207,82 @@ impl<R> Runner<R> {
     /// Runs one iteration of this runner.
     ///
     /// `data` contains the application data used in the [AppRunner](trait.AppRunner.html) callbacks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the document cannot be run, see [try_run](#method.try_run).
     pub fn run<D>(&mut self, data: &mut RunnerData<D>)
     where
         R: AppRunner<D>,
     {
+        if let Err(err) = self.try_run(data) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Runs one iteration of this runner like [run](#method.run), but returns an error instead of
+    /// panicking when the document cannot be run, e.g. when a reference has no target or an
+    /// expression uses a parameter which is not passed.
+    ///
+    /// The failing part of the runner is ended so that the error is reported only once. The other
+    /// parts keep running, in this iteration and the next ones. If several parts fail in the same
+    /// iteration, the first error is returned.
+    pub fn try_run<D>(&mut self, data: &mut RunnerData<D>) -> Result<(), RunError>
+    where
+        R: AppRunner<D>,
+    {
+        let mut result = Ok(());
         for runner in &mut self.runners {
-            runner.run(data, &mut self.app_runner);
+            let run = runner.run(data, &mut self.app_runner);
+            if result.is_ok() {
+                result = run;
+            }
         }
+        result
     }
 
-    /// Checks whether this runner is alive.
-    pub fn is_end(&self) -> bool {
-        for runner in &self.runners {
-            if runner.is_end() {
-                return true;
+    /// Moves this runner from `old` to `new`, a reloaded version of the same document, so that it
+    /// keeps running where it is with the new document.
+    ///
+    /// Every node the runner refers to is looked up in `new` by the label of its nearest labelled
+    /// ancestor, or the root if there is none, and its position from there. It must be of the same
+    /// kind as in `old`. Edits which keep the structure above the running nodes, such as changing
+    /// expressions or adding elements after them, can then be migrated.
+    ///
+    /// If some node cannot be found, the runner is ended so that it never refers to `old` again,
+    /// and the error is returned.
+    pub fn migrate(&mut self, old: &BulletML, new: &BulletML) -> Result<(), MigrationError> {
+        let res = self
+            .runners
+            .iter_mut()
+            .try_for_each(|runner| runner.migrate(old, new));
+        if res.is_err() {
+            for runner in &mut self.runners {
+                runner.end();
             }
         }
-        false
+        res
+    }
+
+    /// Checks whether this runner has ended, i.e. whether all its actions have ended. A runner
+    /// with no action has ended.
+    pub fn is_end(&self) -> bool {
+        self.runners.iter().all(RunnerImpl::is_end)
+    }
+
+    /// Lists the label of each action run by this runner, i.e. the "top" actions of the document
+    /// or the action passed to [new_for_action](#method.new_for_action), and whether it has
+    /// ended. Runners created from a state run no labelled action.
+    ///
+    /// That allows to sequence phases of a pattern, e.g. to start the next phase of a boss when
+    /// one of its top actions ends.
+    pub fn top_actions(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.runners.iter().filter_map(|runner| {
+            runner
+                .label
+                .as_deref()
+                .map(|label| (label, runner.is_end()))
+        })
     }
 }
 
@@ -218,6 +363,28 @@ pub trait AppRunner<D> {
     }
     /// Gets a new random value. The random number generator is managed by the application.
     fn get_rand(&self, data: &mut D) -> f64;
+    /// Gets the value of the application variable `$name`, registered with
+    /// [BulletMLParser::variable](parse/struct.BulletMLParser.html#method.variable).
+    fn get_variable(&self, _data: &D, _name: &str) -> f64 {
+        0.
+    }
+    /// Calls the application function `name`, registered with
+    /// [BulletMLParser::function](parse/struct.BulletMLParser.html#method.function), with its
+    /// evaluated arguments.
+    fn call_function(&self, _data: &mut D, _name: &str, _args: &[f64]) -> f64 {
+        0.
+    }
+    /// Tells the application to run the custom element `name`, registered with
+    /// [BulletMLParser::custom_element](parse/struct.BulletMLParser.html#method.custom_element),
+    /// with its `attributes` and its evaluated `<param>` children.
+    fn do_custom(
+        &mut self,
+        _data: &mut D,
+        _name: &str,
+        _attributes: &[(String, String)],
+        _params: &[f64],
+    ) {
+    }
     #[cfg(test)]
     fn log(&mut self, _data: &mut D, _node: &BulletMLNode) {}
 }
@@ -322,9 +489,29 @@ pub struct RunnerImpl {
     parameters: Parameters,
     repeat_stack: Vec<RepeatElem>,
     ref_stack: Vec<StackedRef>,
+    /// Label of the action run from the start, if any.
+    label: Option<String>,
 }
 
 impl RunnerImpl {
+    fn new_top(bml: &BulletML, bml_type: Option<BulletMLType>, action: NodeId) -> Self {
+        let state = State {
+            bml_type,
+            nodes: Box::new([action]),
+            parameters: Vec::new(),
+        };
+        let runner = RunnerImpl::new(state);
+        match bml.arena[action].get() {
+            BulletMLNode::Action(Some(label)) => runner.with_label(label),
+            _ => runner,
+        }
+    }
+
+    fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
     fn new(state: State) -> Self {
         let act = Some(state.nodes[0]);
         let mut root_nodes = HashSet::new();
@@ -351,12 +538,17 @@ impl RunnerImpl {
             parameters: state.parameters,
             repeat_stack: Vec::new(),
             ref_stack: Vec::new(),
+            label: None,
         }
     }
 
-    fn run<D>(&mut self, data: &mut RunnerData<D>, runner: &mut dyn AppRunner<D>) {
+    fn run<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &mut dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         if self.is_end() {
-            return;
+            return Ok(());
         }
         self.changes(data, runner);
         self.end_turn = runner.get_turn(data.data);
@@ -369,13 +561,16 @@ impl RunnerImpl {
             {
                 self.end = true;
             }
-            return;
+            return Ok(());
         }
         self.act = Some(self.nodes[self.act_iter]);
         if self.act_turn.is_none() {
             self.act_turn = Some(runner.get_turn(data.data));
         }
-        self.run_sub(data, runner);
+        if let Err(err) = self.run_sub(data, runner) {
+            self.end();
+            return Err(err);
+        }
         match self.act {
             None => {
                 self.act_iter += 1;
@@ -385,12 +580,60 @@ impl RunnerImpl {
             }
             Some(act) => self.nodes[self.act_iter] = act,
         }
+        Ok(())
     }
 
     fn is_end(&self) -> bool {
         self.end
     }
 
+    fn end(&mut self) {
+        self.end = true;
+        self.act = None;
+    }
+
+    /// Moves all the nodes of this runner to `new`. The runner is left untouched on error.
+    fn migrate(&mut self, old: &BulletML, new: &BulletML) -> Result<(), MigrationError> {
+        if self.is_end() {
+            return Ok(());
+        }
+        let migrate = |node| reload::migrate_node(old, new, node);
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| migrate(*node))
+            .collect::<Result<Vec<_>, _>>()?;
+        let root_nodes = self
+            .root_nodes
+            .iter()
+            .map(|node| migrate(*node))
+            .collect::<Result<HashSet<_>, _>>()?;
+        let act = self.act.map(migrate).transpose()?;
+        let repeat_acts = self
+            .repeat_stack
+            .iter()
+            .map(|rep| migrate(rep.act))
+            .collect::<Result<Vec<_>, _>>()?;
+        let refs = self
+            .ref_stack
+            .iter()
+            .map(|stacked| Ok((migrate(stacked.ref_id)?, migrate(stacked.prev)?)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.bml_type = new.get_type();
+        self.nodes = nodes.into_boxed_slice();
+        self.root_nodes = root_nodes;
+        self.act = act;
+        for (rep, act) in self.repeat_stack.iter_mut().zip(repeat_acts) {
+            rep.act = act;
+        }
+        for (stacked, (ref_id, prev)) in self.ref_stack.iter_mut().zip(refs) {
+            stacked.ref_id = ref_id;
+            stacked.prev = prev;
+        }
+        Ok(())
+    }
+
     fn is_turn_end(&self) -> bool {
         self.is_end() || self.act_turn.unwrap_or(0) > self.end_turn
     }
@@ -461,7 +704,11 @@ impl RunnerImpl {
         }
     }
 
-    fn run_sub<D>(&mut self, data: &mut RunnerData<D>, runner: &mut dyn AppRunner<D>) {
+    fn run_sub<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &mut dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         let bml = data.bml;
         while let Some(act) = self.act {
             if self.is_turn_end() {
@@ -471,36 +718,42 @@ impl RunnerImpl {
             let mut prev_node = &bml.arena[act];
             let node = &bml.arena[act];
             #[cfg(test)]
-            runner.log(&mut data.data, node.get());
+            runner.log(data.data, node.get());
             match node.get() {
-                BulletMLNode::Bullet { .. } => self.run_bullet(data, runner),
+                BulletMLNode::Bullet { .. } => self.run_bullet(data, runner)?,
                 BulletMLNode::Action { .. } => self.run_action(node),
-                BulletMLNode::Fire { .. } => self.run_fire(data, runner),
-                BulletMLNode::ChangeDirection => self.run_change_direction(data, runner),
-                BulletMLNode::ChangeSpeed => self.run_change_speed(data, runner),
-                BulletMLNode::Accel => self.run_accel(data, runner),
-                BulletMLNode::Wait(expr) => self.run_wait(*expr, data, runner),
-                BulletMLNode::Repeat => self.run_repeat(act, data, runner),
+                BulletMLNode::Fire { .. } => self.run_fire(data, runner)?,
+                BulletMLNode::ChangeDirection => self.run_change_direction(data, runner)?,
+                BulletMLNode::ChangeSpeed => self.run_change_speed(data, runner)?,
+                BulletMLNode::Accel => self.run_accel(data, runner)?,
+                BulletMLNode::Wait(expr) => self.run_wait(expr, data, runner)?,
+                BulletMLNode::Repeat => self.run_repeat(act, data, runner)?,
                 BulletMLNode::BulletRef(label) => {
-                    self.run_ref(act, bml.bullet_refs[label], data, runner)
+                    let target =
+                        Self::get_target(&bml.bullet_refs, label, ReferenceKind::Bullet, act)?;
+                    self.run_ref(act, target, data, runner)?
                 }
                 BulletMLNode::ActionRef(label) => {
-                    self.run_ref(act, bml.action_refs[label], data, runner)
+                    let target =
+                        Self::get_target(&bml.action_refs, label, ReferenceKind::Action, act)?;
+                    self.run_ref(act, target, data, runner)?
                 }
                 BulletMLNode::FireRef(label) => {
-                    self.run_ref(act, bml.fire_refs[label], data, runner)
+                    let target = Self::get_target(&bml.fire_refs, label, ReferenceKind::Fire, act)?;
+                    self.run_ref(act, target, data, runner)?
                 }
                 BulletMLNode::Vanish => self.run_vanish(data, runner),
+                BulletMLNode::Custom {
+                    name,
+                    attributes,
+                    params,
+                } => self.run_custom(name, attributes, params, data, runner)?,
                 _ => (),
             }
             loop {
                 if self.act.is_none() {
                     // Unstack reference if needed.
-                    if self
-                        .ref_stack
-                        .last()
-                        .map_or(false, |stacked| stacked.ref_id == prev)
-                    {
+                    if matches!(self.ref_stack.last(), Some(stacked) if stacked.ref_id == prev) {
                         let top = self.ref_stack.pop().unwrap();
                         prev = top.prev;
                         prev_node = &bml.arena[prev];
@@ -523,7 +776,10 @@ impl RunnerImpl {
                 let (new_act, new_act_node) = if let Some(parent) = parent {
                     let parent_node = &bml.arena[parent];
                     if let BulletMLNode::Repeat = parent_node.get() {
-                        let rep = self.repeat_stack.last_mut().unwrap();
+                        let rep = self
+                            .repeat_stack
+                            .last_mut()
+                            .ok_or_else(|| RunError::new_unbalanced_repeat(parent))?;
                         rep.iter += 1;
                         if rep.iter < rep.end {
                             // Unfinished Repeat, set act and break loop.
@@ -535,13 +791,25 @@ impl RunnerImpl {
                     }
                     (parent, parent_node)
                 } else {
-                    panic!("A run node must have a parent");
+                    return Err(RunError::new_detached_node(prev));
                 };
 
                 prev = new_act;
                 prev_node = new_act_node;
             }
         }
+        Ok(())
+    }
+
+    fn get_target(
+        refs: &HashMap<String, NodeId>,
+        label: &str,
+        kind: ReferenceKind,
+        act: NodeId,
+    ) -> Result<NodeId, RunError> {
+        refs.get(label)
+            .copied()
+            .ok_or_else(|| RunError::new_unresolved_reference(label.to_string(), kind, act))
     }
 
     fn get_first_child_id_matching<M, N>(
@@ -561,13 +829,13 @@ impl RunnerImpl {
         None
     }
 
-    fn get_first_child_matching<M, N>(
-        arena: &Arena<BulletMLNode>,
+    fn get_first_child_matching<'a, M, N>(
+        arena: &'a Arena<BulletMLNode>,
         parent: NodeId,
         m: M,
     ) -> Option<N>
     where
-        M: Fn(&BulletMLNode) -> Option<N>,
+        M: Fn(&'a BulletMLNode) -> Option<N>,
     {
         for child in parent.children(arena) {
             let child_node = &arena[child];
@@ -604,11 +872,11 @@ impl RunnerImpl {
     fn get_direction<D>(
         &mut self,
         dir_type: Option<DirectionType>,
-        expr: BulletMLExpression,
+        expr: &BulletMLExpression,
         data: &mut RunnerData<D>,
         runner: &dyn AppRunner<D>,
-    ) -> f64 {
-        let direction = self.get_number_contents(expr, data, runner);
+    ) -> Result<f64, RunError> {
+        let direction = self.get_number_contents(expr, data, runner)?;
         let (mut direction, aim) = match dir_type {
             None => (direction, true),
             Some(DirectionType::Aim) => (direction, true),
@@ -641,28 +909,34 @@ impl RunnerImpl {
             direction += 360.
         }
         self.prev_dir.set(direction);
-        direction
+        Ok(direction)
     }
 
-    fn set_direction<D>(&mut self, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) {
+    fn set_direction<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         if let Some(act) = self.act {
+            let bml = data.bml;
             let direction =
-                Self::get_first_child_matching(&data.bml.arena, act, BulletMLNode::match_direction);
+                Self::get_first_child_matching(&bml.arena, act, BulletMLNode::match_direction);
             if let Some((dir_type, dir)) = direction {
-                let direction = self.get_direction(dir_type, dir, data, runner);
+                let direction = self.get_direction(dir_type, dir, data, runner)?;
                 self.dir.set(direction);
             }
         }
+        Ok(())
     }
 
     fn get_speed<D>(
         &mut self,
         spd_type: Option<SpeedType>,
-        expr: BulletMLExpression,
+        expr: &BulletMLExpression,
         data: &mut RunnerData<D>,
         runner: &dyn AppRunner<D>,
-    ) -> f64 {
-        let mut speed = self.get_number_contents(expr, data, runner);
+    ) -> Result<f64, RunError> {
+        let mut speed = self.get_number_contents(expr, data, runner)?;
         speed = match spd_type {
             None => speed,
             Some(SpeedType::Absolute) => speed,
@@ -676,24 +950,33 @@ impl RunnerImpl {
             }
         };
         self.prev_spd.set(speed);
-        speed
+        Ok(speed)
     }
 
-    fn set_speed<D>(&mut self, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) {
+    fn set_speed<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         if let Some(act) = self.act {
-            let speed =
-                Self::get_first_child_matching(&data.bml.arena, act, BulletMLNode::match_speed);
+            let bml = data.bml;
+            let speed = Self::get_first_child_matching(&bml.arena, act, BulletMLNode::match_speed);
             if let Some((spd_type, spd)) = speed {
-                let speed = self.get_speed(spd_type, spd, data, runner);
+                let speed = self.get_speed(spd_type, spd, data, runner)?;
                 self.spd.set(speed);
             }
         }
+        Ok(())
     }
 
-    fn run_bullet<D>(&mut self, data: &mut RunnerData<D>, runner: &mut dyn AppRunner<D>) {
+    fn run_bullet<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &mut dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         let arena = &data.bml.arena;
-        self.set_speed(data, runner);
-        self.set_direction(data, runner);
+        self.set_speed(data, runner)?;
+        self.set_direction(data, runner)?;
         if !self.spd.is_valid() {
             let default = runner.get_default_speed();
             self.spd.set(default);
@@ -718,20 +1001,25 @@ impl RunnerImpl {
             runner.create_bullet(data.data, state, self.dir.get(), self.spd.get());
         }
         self.act = None;
+        Ok(())
     }
 
-    fn run_fire<D>(&mut self, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) {
+    fn run_fire<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         self.shot_init();
-        self.set_speed(data, runner);
-        self.set_direction(data, runner);
+        self.set_speed(data, runner)?;
+        self.set_direction(data, runner)?;
         if let Some(act) = self.act {
             let arena = &data.bml.arena;
             let bullet =
-                Self::get_first_child_id_matching(arena, act, BulletMLNode::match_any_bullet);
-            if bullet.is_some() {
-                self.act = bullet;
-            }
+                Self::get_first_child_id_matching(arena, act, BulletMLNode::match_any_bullet)
+                    .ok_or_else(|| RunError::new_missing_bullet(act))?;
+            self.act = Some(bullet);
         }
+        Ok(())
     }
 
     fn run_action(&mut self, node: &Node<BulletMLNode>) {
@@ -740,29 +1028,36 @@ impl RunnerImpl {
 
     fn run_wait<D>(
         &mut self,
-        expr: BulletMLExpression,
+        expr: &BulletMLExpression,
         data: &mut RunnerData<D>,
         runner: &dyn AppRunner<D>,
-    ) {
-        let frame = self.get_number_contents(expr, data, runner);
+    ) -> Result<(), RunError> {
+        let frame = self.get_number_contents(expr, data, runner)?;
         self.do_wait(frame as u32);
         self.act = None;
+        Ok(())
     }
 
-    fn run_repeat<D>(&mut self, act: NodeId, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) {
-        let times = Self::get_first_child_matching(&data.bml.arena, act, BulletMLNode::match_times);
-        if let Some(times) = times {
-            let times = self.get_number_contents(times, data, runner) as usize;
-            let arena = &data.bml.arena;
-            let action =
-                Self::get_first_child_id_matching(arena, act, BulletMLNode::match_any_action);
-            self.repeat_stack.push(RepeatElem {
-                iter: 0,
-                end: times,
-                act: action.unwrap(),
-            });
-            self.act = action;
-        }
+    fn run_repeat<D>(
+        &mut self,
+        act: NodeId,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
+        let bml = data.bml;
+        let times = Self::get_first_child_matching(&bml.arena, act, BulletMLNode::match_times)
+            .ok_or_else(|| RunError::new_missing_times(act))?;
+        let times = self.get_number_contents(times, data, runner)? as usize;
+        let arena = &bml.arena;
+        let action = Self::get_first_child_id_matching(arena, act, BulletMLNode::match_any_action)
+            .ok_or_else(|| RunError::new_missing_action(act))?;
+        self.repeat_stack.push(RepeatElem {
+            iter: 0,
+            end: times,
+            act: action,
+        });
+        self.act = Some(action);
+        Ok(())
     }
 
     fn run_ref<D>(
@@ -771,8 +1066,8 @@ impl RunnerImpl {
         ref_id: NodeId,
         data: &mut RunnerData<D>,
         runner: &dyn AppRunner<D>,
-    ) {
-        let new_parameters = self.get_parameters(data, runner);
+    ) -> Result<(), RunError> {
+        let new_parameters = self.get_parameters(act, data, runner)?;
         let prev_parameters = std::mem::replace(&mut self.parameters, new_parameters);
         self.ref_stack.push(StackedRef {
             ref_id,
@@ -780,27 +1075,34 @@ impl RunnerImpl {
             prev_parameters,
         });
         self.act = Some(ref_id);
+        Ok(())
     }
 
-    fn run_change_direction<D>(&mut self, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) {
+    fn run_change_direction<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         if let Some(act) = self.act {
-            let arena = &data.bml.arena;
+            let bml = data.bml;
+            let arena = &bml.arena;
             let term = Self::get_first_child_matching(arena, act, BulletMLNode::match_term);
             if let Some(term) = term {
                 let direction =
                     Self::get_first_child_matching(arena, act, BulletMLNode::match_direction);
                 if let Some((dir_type, dir)) = direction {
-                    let term = self.get_number_contents(term, data, runner) as u32;
+                    let term = self.get_number_contents(term, data, runner)? as u32;
                     let (dir, seq) = if let Some(DirectionType::Sequence) = dir_type {
-                        (self.get_number_contents(dir, data, runner), true)
+                        (self.get_number_contents(dir, data, runner)?, true)
                     } else {
-                        (self.get_direction(dir_type, dir, data, runner), false)
+                        (self.get_direction(dir_type, dir, data, runner)?, false)
                     };
                     self.calc_change_direction(dir, term, seq, data, runner);
                 }
             }
         }
         self.act = None;
+        Ok(())
     }
 
     fn calc_change_direction<D>(
@@ -842,25 +1144,31 @@ impl RunnerImpl {
         }
     }
 
-    fn run_change_speed<D>(&mut self, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) {
+    fn run_change_speed<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         if let Some(act) = self.act {
-            let arena = &data.bml.arena;
+            let bml = data.bml;
+            let arena = &bml.arena;
             let term = Self::get_first_child_matching(arena, act, BulletMLNode::match_term);
             if let Some(term) = term {
                 let speed = Self::get_first_child_matching(arena, act, BulletMLNode::match_speed);
                 if let Some((spd_type, spd)) = speed {
-                    let term = self.get_number_contents(term, data, runner) as u32;
+                    let term = self.get_number_contents(term, data, runner)? as u32;
                     let spd = if let Some(SpeedType::Sequence) = spd_type {
-                        self.get_number_contents(spd, data, runner) * f64::from(term)
+                        self.get_number_contents(spd, data, runner)? * f64::from(term)
                             + runner.get_bullet_speed(data.data)
                     } else {
-                        self.get_speed(spd_type, spd, data, runner)
+                        self.get_speed(spd_type, spd, data, runner)?
                     };
                     self.calc_change_speed(spd, term, data, runner);
                 }
             }
         }
         self.act = None;
+        Ok(())
     }
 
     fn calc_change_speed<D>(
@@ -876,12 +1184,17 @@ impl RunnerImpl {
         self.change_spd = Some(LinearFunc::new(act_turn, final_turn, spd_first, speed));
     }
 
-    fn run_accel<D>(&mut self, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) {
+    fn run_accel<D>(
+        &mut self,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
         if let Some(act) = self.act {
-            let arena = &data.bml.arena;
+            let bml = data.bml;
+            let arena = &bml.arena;
             let term = Self::get_first_child_matching(arena, act, BulletMLNode::match_term);
             if let Some(term) = term {
-                let term = self.get_number_contents(term, data, runner) as u32;
+                let term = self.get_number_contents(term, data, runner)? as u32;
                 let horizontal =
                     Self::get_first_child_matching(arena, act, BulletMLNode::match_horizontal);
                 let vertical =
@@ -890,7 +1203,7 @@ impl RunnerImpl {
                     if let Some((v_type, v)) = vertical {
                         self.accel_x = self.calc_accel_xy(
                             runner.get_bullet_speed_x(),
-                            self.get_number_contents(v, data, runner),
+                            self.get_number_contents(v, data, runner)?,
                             term,
                             v_type,
                         );
@@ -898,7 +1211,7 @@ impl RunnerImpl {
                     if let Some((h_type, h)) = horizontal {
                         self.accel_y = self.calc_accel_xy(
                             runner.get_bullet_speed_y(),
-                            self.get_number_contents(h, data, runner),
+                            self.get_number_contents(h, data, runner)?,
                             term,
                             h_type,
                         );
@@ -907,7 +1220,7 @@ impl RunnerImpl {
                     if let Some((h_type, h)) = horizontal {
                         self.accel_x = self.calc_accel_xy(
                             runner.get_bullet_speed_x(),
-                            self.get_number_contents(h, data, runner),
+                            self.get_number_contents(h, data, runner)?,
                             term,
                             h_type,
                         );
@@ -915,7 +1228,7 @@ impl RunnerImpl {
                     if let Some((v_type, v)) = vertical {
                         self.accel_y = self.calc_accel_xy(
                             runner.get_bullet_speed_y(),
-                            self.get_number_contents(v, data, runner),
+                            self.get_number_contents(v, data, runner)?,
                             term,
                             v_type,
                         );
@@ -924,6 +1237,7 @@ impl RunnerImpl {
             }
         }
         self.act = None;
+        Ok(())
     }
 
     fn calc_accel_xy(
@@ -948,46 +1262,116 @@ impl RunnerImpl {
         self.act = None;
     }
 
-    fn get_parameters<D>(&self, data: &mut RunnerData<D>, runner: &dyn AppRunner<D>) -> Parameters {
-        let children = self.act.unwrap().children(&data.bml.arena);
+    fn run_custom<D>(
+        &mut self,
+        name: &str,
+        attributes: &[(String, String)],
+        params: &[BulletMLExpression],
+        data: &mut RunnerData<D>,
+        runner: &mut dyn AppRunner<D>,
+    ) -> Result<(), RunError> {
+        let params = params
+            .iter()
+            .map(|param| self.get_number_contents(param, data, runner))
+            .collect::<Result<Vec<_>, _>>()?;
+        runner.do_custom(data.data, name, attributes, &params);
+        self.act = None;
+        Ok(())
+    }
+
+    fn get_parameters<D>(
+        &self,
+        act: NodeId,
+        data: &mut RunnerData<D>,
+        runner: &dyn AppRunner<D>,
+    ) -> Result<Parameters, RunError> {
+        let bml = data.bml;
+        let children = act.children(&bml.arena);
         let mut parameters = Vec::new();
         for child in children {
-            let child_node = &data.bml.arena[child];
+            let child_node = &bml.arena[child];
             if let BulletMLNode::Param(expr) = child_node.get() {
-                parameters.push(self.get_number_contents(*expr, data, runner));
+                parameters.push(self.get_number_contents(expr, data, runner)?);
             }
         }
-        parameters
+        Ok(parameters)
     }
 
     fn get_number_contents<D>(
         &self,
-        expr: BulletMLExpression,
+        expr: &BulletMLExpression,
         data: &mut RunnerData<D>,
         runner: &dyn AppRunner<D>,
-    ) -> f64 {
+    ) -> Result<f64, RunError> {
         match expr {
-            BulletMLExpression::Const(value) => value,
-            BulletMLExpression::Expr(expr) => {
-                let rank = runner.get_rank(data.data);
-                let expr_ref = expr.from(&data.bml.expr_slab.ps);
-                use fasteval::Evaler;
-                expr_ref
-                    .eval(
-                        &data.bml.expr_slab,
-                        &mut |name: &str, args: Vec<f64>| match (name, args.as_slice()) {
-                            ("v", &[i]) => Some(self.parameters[i as usize - 1]),
-                            ("rank", &[]) => Some(rank),
-                            ("rand", &[]) => Some(runner.get_rand(data.data)),
-                            _ => None,
-                        },
-                    )
-                    .unwrap()
+            BulletMLExpression::Const(value) => Ok(*value),
+            BulletMLExpression::Expr { expr, .. } => {
+                let mut context = EvalContext {
+                    runner: self,
+                    data: data.data,
+                    app_runner: runner,
+                    missing_parameter: None,
+                };
+                let value = expr.eval(&mut context);
+                match context.missing_parameter {
+                    // The node being run is the one the expression belongs to.
+                    Some(index) => Err(RunError::new_missing_parameter(
+                        index,
+                        self.act.unwrap_or(self.nodes[self.act_iter]),
+                    )),
+                    None => Ok(value),
+                }
             }
         }
     }
 }
 
+/// Provides the values of the variables of an expression evaluated by a runner.
+struct EvalContext<'a, D> {
+    runner: &'a RunnerImpl,
+    data: &'a mut D,
+    app_runner: &'a dyn AppRunner<D>,
+    /// First parameter used by the expression but not passed to the runner.
+    missing_parameter: Option<usize>,
+}
+
+impl<'a, D> Context for EvalContext<'a, D> {
+    fn parameter(&mut self, index: usize) -> f64 {
+        match self.runner.parameters.get(index - 1) {
+            Some(value) => *value,
+            None => {
+                self.missing_parameter.get_or_insert(index);
+                0.
+            }
+        }
+    }
+
+    fn rank(&mut self) -> f64 {
+        self.app_runner.get_rank(self.data)
+    }
+
+    fn rand(&mut self) -> f64 {
+        self.app_runner.get_rand(self.data)
+    }
+
+    fn loop_index(&mut self, depth: usize) -> f64 {
+        self.runner
+            .repeat_stack
+            .iter()
+            .rev()
+            .nth(depth)
+            .map_or(0., |rep| rep.iter as f64)
+    }
+
+    fn variable(&mut self, name: &str) -> f64 {
+        self.app_runner.get_variable(self.data, name)
+    }
+
+    fn function(&mut self, name: &str, args: &[f64]) -> f64 {
+        self.app_runner.call_function(self.data, name, args)
+    }
+}
+
 #[derive(Debug)]
 struct RepeatElem {
     iter: usize,
@@ -1138,6 +1522,9 @@ mod tests {
             data.logs[self.index]
                 .log
                 .push(format!("create_bullet({}, {})", direction, speed));
+            // Sends every state through serde to check that the runners carry on the same way.
+            #[cfg(feature = "serde")]
+            let state = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
             let runner = Runner::new_from_state(TestAppRunner::new(0), state);
             self.new_runners.push(runner);
         }
@@ -1164,6 +1551,33 @@ mod tests {
             0.42
         }
 
+        fn get_variable(&self, _data: &TestAppData<'a>, name: &str) -> f64 {
+            match name {
+                "hp" => 50.,
+                _ => 0.,
+            }
+        }
+
+        fn call_function(&self, data: &mut TestAppData<'a>, name: &str, args: &[f64]) -> f64 {
+            data.logs[self.index]
+                .log
+                .push(format!("call_function({}, {:?})", name, args));
+            args.iter().sum()
+        }
+
+        fn do_custom(
+            &mut self,
+            data: &mut TestAppData<'a>,
+            name: &str,
+            attributes: &[(String, String)],
+            params: &[f64],
+        ) {
+            data.logs[self.index].log.push(format!(
+                "do_custom({}, {:?}, {:?})",
+                name, attributes, params
+            ));
+        }
+
         fn log(&mut self, data: &mut TestAppData<'a>, node: &BulletMLNode) {
             data.logs[self.index].log.push(format!("{:?}", node));
         }
@@ -1174,7 +1588,7 @@ mod tests {
         runners: Vec<Runner<TestAppRunner>>,
     }
 
-    impl<'a> TestManager {
+    impl TestManager {
         fn new(bml: BulletML) -> Self {
             TestManager {
                 bml,
@@ -1281,6 +1695,532 @@ mod tests {
         TestLogs(logs);
     }
 
+    #[test]
+    fn test_custom_element() {
+        let bml = BulletMLParser::new()
+            .custom_element("shake")
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <shake duration="10">
+        <param>$rank * 2</param>
+        <param>3</param>
+    </shake>
+    <wait>1</wait>
+    <shake />
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut manager = TestManager::new(bml);
+        let mut logs = Vec::new();
+        manager.run_test(3, &mut logs);
+        logs[0].assert_log(r#"=== 0"#, 1);
+        logs[0].assert_log(r#"Action(Some("top"))"#, 1);
+        logs[0].assert_log(
+            r#"Custom { name: "shake", attributes: [("duration", "10")], params: [Expr("$rank * 2"), Const(3.0)] }"#,
+            1,
+        );
+        logs[0].assert_log(r#"do_custom(shake, [("duration", "10")], [2.0, 3.0])"#, 1);
+        logs[0].assert_log(r#"Wait(Const(1.0))"#, 1);
+        logs[0].assert_log(r#"=== 1"#, 1);
+        logs[0].assert_log(r#"Custom { name: "shake", attributes: [], params: [] }"#, 1);
+        logs[0].assert_log(r#"do_custom(shake, [], [])"#, 1);
+        logs[0].assert_log(r#"=== 2"#, 1);
+        TestLogs(logs);
+    }
+
+    #[test]
+    fn test_for_action() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="ring">
+    <fire>
+        <direction type="absolute">$1</direction>
+        <bullet />
+    </fire>
+    <wait>$2</wait>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut manager = TestManager::new(bml);
+        let mut runner =
+            Runner::new_for_action(TestAppRunner::new(0), &manager.bml, "ring", vec![90., 2.])
+                .unwrap();
+        let mut logs = Vec::new();
+        manager.runners.push(
+            Runner::new_for_action(TestAppRunner::new(0), &manager.bml, "ring", vec![90., 2.])
+                .unwrap(),
+        );
+        for i in 0..3 {
+            manager.run(i, &mut logs);
+        }
+        logs[0].assert_log(r#"=== 0"#, 1);
+        logs[0].assert_log(r#"Action(Some("ring"))"#, 1);
+        logs[0].assert_log(r#"Fire(None)"#, 1);
+        logs[0].assert_log(r#"Bullet(None)"#, 1);
+        logs[0].assert_log(r#"create_simple_bullet(90, 10)"#, 1);
+        logs[0].assert_log(r#"Wait(Expr("$2"))"#, 1);
+        logs[0].assert_log(r#"=== 1"#, 1);
+        logs[0].assert_log(r#"=== 2"#, 1);
+        TestLogs(logs);
+        assert!(manager.runners[0].is_end());
+
+        let err = runner
+            .init_for_action(&manager.bml, "top", Vec::new())
+            .unwrap_err();
+        assert_matches!(err, RunError::UnknownAction { ref label, .. } if label == "top");
+        let err = runner
+            .init_for_action(&manager.bml, "ring", vec![90.])
+            .unwrap_err();
+        assert_matches!(
+            err,
+            RunError::ParameterCount {
+                expected: 2,
+                found: 1,
+                ..
+            }
+        );
+        assert!(!runner.is_end());
+    }
+
+    #[test]
+    fn test_top_actions() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top1">
+    <wait>1</wait>
+</action>
+<action label="top2">
+    <wait>3</wait>
+</action>
+<action label="ring">
+    <wait>1</wait>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut logs = vec![TestLog::new("logs[0]".to_string())];
+        let mut runner = Runner::new(TestAppRunner::new(0), &bml);
+        let mut statuses = Vec::new();
+        while !runner.is_end() {
+            runner.run(&mut RunnerData {
+                bml: &bml,
+                data: &mut TestAppData { logs: &mut logs },
+            });
+            runner.next_turn();
+            statuses.push(runner.top_actions().map(|(_, end)| end).collect::<Vec<_>>());
+        }
+        assert_eq!(
+            statuses,
+            vec![
+                vec![false, false],
+                vec![true, false],
+                vec![true, false],
+                vec![true, true],
+            ]
+        );
+        assert_eq!(
+            runner.top_actions().collect::<Vec<_>>(),
+            vec![("top1", true), ("top2", true)]
+        );
+
+        runner.init_for_action(&bml, "ring", Vec::new()).unwrap();
+        assert_eq!(
+            runner.top_actions().collect::<Vec<_>>(),
+            vec![("ring", false)]
+        );
+        runner.init_from_state(State::for_action(&bml, "ring", Vec::new()).unwrap());
+        assert_eq!(runner.top_actions().count(), 0);
+        assert!(Runner::<()>::default().is_end());
+    }
+
+    #[test]
+    fn test_try_run() {
+        let mut bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="ring">
+    <fire>
+        <direction type="absolute">$1</direction>
+        <bullet />
+    </fire>
+</action>
+<action label="missing_action">
+    <repeat>
+        <times>2</times>
+        <action />
+    </repeat>
+</action>
+<action label="unresolved">
+    <wait>1</wait>
+    <actionRef label="gone" />
+</action>
+<action label="missing_times">
+    <repeat>
+        <action />
+    </repeat>
+</action>
+<action label="missing_bullet">
+    <fire />
+</action>
+<action label="gone" />
+</bulletml>"##,
+            )
+            .unwrap();
+        bml.action_refs.remove("gone");
+        let repeat = bml
+            .arena
+            .iter()
+            .find(|node| matches!(node.get(), BulletMLNode::Repeat))
+            .map(|node| bml.arena.get_node_id(node).unwrap())
+            .unwrap();
+        let action = repeat.children(&bml.arena).nth(1).unwrap();
+        action.detach(&mut bml.arena);
+
+        let mut logs = vec![TestLog::new("logs[0]".to_string())];
+        let mut run = |label: &str| {
+            // Bypasses the parameter count check of new_for_action.
+            let state = State {
+                bml_type: None,
+                nodes: Box::new([bml.action_refs[label]]),
+                parameters: Vec::new(),
+            };
+            let mut runner = Runner::new_from_state(TestAppRunner::new(0), state);
+            let mut result = Ok(());
+            for _ in 0..3 {
+                result = result.and(runner.try_run(&mut RunnerData {
+                    bml: &bml,
+                    data: &mut TestAppData { logs: &mut logs },
+                }));
+                runner.app_runner.next_turn();
+            }
+            assert!(runner.is_end());
+            result.unwrap_err()
+        };
+
+        let err = run("ring");
+        assert_matches!(err, RunError::MissingParameter { index: 1, .. });
+        assert_matches!(
+            bml.arena[err.node().unwrap()].get(),
+            BulletMLNode::Fire(None)
+        );
+        let err = run("missing_action");
+        assert_matches!(err, RunError::MissingAction { node, .. } if node == repeat);
+        let err = run("missing_times");
+        assert_matches!(bml.arena[err.node().unwrap()].get(), BulletMLNode::Repeat);
+        assert_matches!(err, RunError::MissingTimes { .. });
+        let err = run("missing_bullet");
+        assert_matches!(
+            bml.arena[err.node().unwrap()].get(),
+            BulletMLNode::Fire(None)
+        );
+        assert_matches!(err, RunError::MissingBullet { .. });
+        let err = run("unresolved");
+        assert_matches!(
+            err,
+            RunError::UnresolvedReference {
+                ref label,
+                kind: ReferenceKind::Action,
+                ..
+            } if label == "gone"
+        );
+        assert_matches!(
+            bml.arena[err.node().unwrap()].get(),
+            BulletMLNode::ActionRef(..)
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Unresolved label gone in element actionRef at node {}",
+                err.node().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_try_run_keeps_running() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top1">
+    <wait>$1</wait>
+</action>
+<action label="top2">
+    <fire>
+        <bullet />
+    </fire>
+</action>
+<action label="top3">
+    <changeSpeed>
+        <speed>$2</speed>
+        <term>1</term>
+    </changeSpeed>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut logs = TestLogs(vec![TestLog::new("logs[0]".to_string())]);
+        let mut runner = Runner::new(TestAppRunner::new(0), &bml);
+        let err = runner
+            .try_run(&mut RunnerData {
+                bml: &bml,
+                data: &mut TestAppData { logs: &mut logs.0 },
+            })
+            .unwrap_err();
+        assert_matches!(err, RunError::MissingParameter { index: 1, .. });
+        assert_eq!(
+            runner.top_actions().collect::<Vec<_>>(),
+            vec![("top1", true), ("top2", false), ("top3", true)]
+        );
+        let log = &mut logs.0[0];
+        log.assert_log(r#"Action(Some("top1"))"#, 1);
+        log.assert_log(r#"Wait(Expr("$1"))"#, 1);
+        log.assert_log(r#"Action(Some("top2"))"#, 1);
+        log.assert_log(r#"Fire(None)"#, 1);
+        log.assert_log(r#"Bullet(None)"#, 1);
+        log.assert_log(r#"create_simple_bullet(0, 10)"#, 1);
+        log.assert_log(r#"Action(Some("top3"))"#, 1);
+        log.assert_log(r#"ChangeSpeed"#, 1);
+    }
+
+    #[test]
+    fn test_loop_index() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <repeat>
+        <times>2</times>
+        <action>
+            <repeat>
+                <times>3</times>
+                <actionRef label="shot" />
+            </repeat>
+        </action>
+    </repeat>
+    <wait>$loop.index + 1</wait>
+</action>
+<action label="shot">
+    <fire>
+        <direction type="absolute">$loop.1.index * 100 + $loop.index * 10 + $loop.2.index</direction>
+        <bullet />
+    </fire>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut manager = TestManager::new(bml);
+        let mut logs = Vec::new();
+        manager.run_test(2, &mut logs);
+        logs[0].assert_log(r#"=== 0"#, 1);
+        logs[0].assert_log(r#"Action(Some("top"))"#, 1);
+        logs[0].assert_log(r#"Repeat"#, 1);
+        for i in 0..2 {
+            logs[0].assert_log(r#"Action(None)"#, 1);
+            logs[0].assert_log(r#"Repeat"#, 1);
+            for j in 0..3 {
+                logs[0].assert_log(r#"ActionRef("shot")"#, 1);
+                logs[0].assert_log(r#"Action(Some("shot"))"#, 1);
+                logs[0].assert_log(r#"Fire(None)"#, 1);
+                logs[0].assert_log(r#"Bullet(None)"#, 1);
+                logs[0].assert_log(
+                    &format!(r#"create_simple_bullet({}, 10)"#, i * 100 + j * 10),
+                    1,
+                );
+            }
+        }
+        logs[0].assert_log(r#"Wait(Expr("$loop.index + 1"))"#, 1);
+        logs[0].assert_log(r#"=== 1"#, 1);
+        TestLogs(logs);
+    }
+
+    #[test]
+    fn test_app_expressions() {
+        let bml = BulletMLParser::new()
+            .variable("hp")
+            .function("angleTo")
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <fire>
+        <direction type="absolute">angleTo($hp, max(1, 2)) + abs(-1)</direction>
+        <bullet />
+    </fire>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut manager = TestManager::new(bml);
+        let mut logs = Vec::new();
+        manager.run_test(1, &mut logs);
+        logs[0].assert_log(r#"=== 0"#, 1);
+        logs[0].assert_log(r#"Action(Some("top"))"#, 1);
+        logs[0].assert_log(r#"Fire(None)"#, 1);
+        logs[0].assert_log(r#"call_function(angleTo, [50.0, 2.0])"#, 1);
+        logs[0].assert_log(r#"Bullet(None)"#, 1);
+        logs[0].assert_log(r#"create_simple_bullet(53, 10)"#, 1);
+        TestLogs(logs);
+    }
+
+    #[test]
+    fn test_specialize_for_rank() {
+        let mut bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <fire>
+        <direction type="absolute">360 / 16 + $rank * 10</direction>
+        <bullet />
+    </fire>
+    <wait>$rank * 4</wait>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        bml.specialize_for_rank(0.5);
+        let mut manager = TestManager::new(bml);
+        let mut logs = Vec::new();
+        manager.run_test(3, &mut logs);
+        logs[0].assert_log(r#"=== 0"#, 1);
+        logs[0].assert_log(r#"Action(Some("top"))"#, 1);
+        logs[0].assert_log(r#"Fire(None)"#, 1);
+        logs[0].assert_log(r#"Bullet(None)"#, 1);
+        logs[0].assert_log(r#"create_simple_bullet(27.5, 10)"#, 1);
+        logs[0].assert_log(r#"Wait(Expr("$rank * 4"))"#, 1);
+        logs[0].assert_log(r#"=== 1"#, 1);
+        logs[0].assert_log(r#"=== 2"#, 1);
+        TestLogs(logs);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let v1 = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <repeat>
+        <times>3</times>
+        <actionRef label="shot" />
+    </repeat>
+</action>
+<action label="shot">
+    <fire>
+        <bullet />
+    </fire>
+    <wait>10</wait>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let v2 = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <repeat>
+        <times>3</times>
+        <actionRef label="shot" />
+    </repeat>
+</action>
+<action label="shot">
+    <fire>
+        <speed>2</speed>
+        <bullet />
+    </fire>
+    <wait>5</wait>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut manager = TestManager::new(v1);
+        let mut logs = Vec::new();
+        manager.run_test(15, &mut logs);
+        manager.runners[0].migrate(&manager.bml, &v2).unwrap();
+        manager.bml = v2;
+        for i in 15..30 {
+            manager.run(i, &mut logs);
+        }
+        logs[0].assert_log(r#"=== 0"#, 1);
+        logs[0].assert_log(r#"Action(Some("top"))"#, 1);
+        logs[0].assert_log(r#"Repeat"#, 1);
+        for (i, (speed, wait)) in [(10, 10), (10, 10), (2, 5)].iter().enumerate() {
+            if i > 0 {
+                logs[0].assert_log(&format!(r#"=== {}"#, i * 10), 1);
+            }
+            logs[0].assert_log(r#"ActionRef("shot")"#, 1);
+            logs[0].assert_log(r#"Action(Some("shot"))"#, 1);
+            logs[0].assert_log(r#"Fire(None)"#, 1);
+            logs[0].assert_log(r#"Bullet(None)"#, 1);
+            logs[0].assert_log(&format!(r#"create_simple_bullet(0, {})"#, speed), 1);
+            logs[0].assert_log(&format!(r#"Wait(Const({}.0))"#, wait), 1);
+            for j in 1..*wait {
+                logs[0].assert_log(&format!(r#"=== {}"#, i * 10 + j), 1);
+            }
+        }
+        logs[0].assert_log(r#"=== 25"#, 1);
+        assert!(manager.runners[0].is_end());
+        TestLogs(logs);
+    }
+
+    #[test]
+    fn test_migrate_missing_node() {
+        let v1 = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <actionRef label="shot" />
+</action>
+<action label="shot">
+    <wait>10</wait>
+    <wait>10</wait>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let v2 = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <wait>10</wait>
+</action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let mut manager = TestManager::new(v1);
+        let mut logs = Vec::new();
+        manager.run_test(5, &mut logs);
+        assert!(!manager.runners[0].is_end());
+        let err = manager.runners[0].migrate(&manager.bml, &v2).unwrap_err();
+        assert_matches!(err, MigrationError::MissingNode { ref path, .. } if path == "action(shot)/1");
+        assert!(manager.runners[0].is_end());
+        manager.bml = v2;
+        manager.run(5, &mut logs);
+        logs[0].assert_log(r#"=== 0"#, 1);
+        logs[0].assert_log(r#"Action(Some("top"))"#, 1);
+        logs[0].assert_log(r#"ActionRef("shot")"#, 1);
+        logs[0].assert_log(r#"Action(Some("shot"))"#, 1);
+        logs[0].assert_log(r#"Wait(Const(10.0))"#, 1);
+        for i in 1..5 {
+            logs[0].assert_log(&format!(r#"=== {}"#, i), 1);
+        }
+        TestLogs(logs);
+    }
+
     #[test]
     fn test_bulletsmorph_double_seduction() {
         let bml = BulletMLParser::with_capacities(12, 128)
@@ -1460,7 +2400,7 @@ mod tests {
             let mut spd = 1.6;
             for j in 0..1 {
                 logs[i].assert_log(r#"Action(None)"#, 1);
-                logs[i].assert_log(r#"Wait(Expr(ExpressionI(27)))"#, 1);
+                logs[i].assert_log(r#"Wait(Expr("$1"))"#, 1);
                 for k in 0..v1s[(i - 3) / 8 % 12] {
                     logs[i].assert_log(&format!(r#"=== {}"#, (i - 3) / 8 * 5 + k + 3), 1);
                 }
@@ -1532,7 +2472,7 @@ mod tests {
         logs[0].assert_log(r#"=== 1"#, 1);
         logs[0].assert_log(r#"do_change_speed(0)"#, 1);
         logs[0].assert_log(r#"ChangeSpeed"#, 1);
-        logs[0].assert_log(r#"Wait(Expr(ExpressionI(1)))"#, 1);
+        logs[0].assert_log(r#"Wait(Expr("60-$rank*50"))"#, 1);
         logs[0].assert_log(r#"=== 2"#, 1);
         logs[0].assert_log(r#"do_change_speed(1)"#, 1);
         logs[0].assert_log(r#"=== 3"#, 1);
@@ -0,0 +1,380 @@
+use crate::analysis;
+use crate::errors::{ParseErrorPos, ReferenceKind};
+use crate::expr::{self, AppNames};
+use crate::tree::{BulletML, BulletMLExpression, BulletMLNode, ExpressionSource};
+use indextree::{Arena, NodeId};
+use serde_crate::de::Error;
+use serde_crate::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+
+/// Expressions are serialized as their source, which is compiled again when deserialized.
+impl Serialize for BulletMLExpression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            BulletMLExpression::Const(value) => {
+                serializer.serialize_newtype_variant("BulletMLExpression", 0, "Const", value)
+            }
+            BulletMLExpression::Expr { source, .. } => {
+                serializer.serialize_newtype_variant("BulletMLExpression", 1, "Expr", source)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate", rename = "BulletMLExpression")]
+enum ExpressionData {
+    Const(f64),
+    Expr(ExpressionSource),
+}
+
+/// Deserializes an expression and compiles it. The application variables and functions are
+/// accepted without being registered since the expression was checked when first parsed.
+impl<'de> Deserialize<'de> for BulletMLExpression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match ExpressionData::deserialize(deserializer)? {
+            ExpressionData::Const(value) => BulletMLExpression::Const(value),
+            ExpressionData::Expr(source) => BulletMLExpression::Expr {
+                expr: expr::compile(source.text(), &AppNames::any()).map_err(|err| {
+                    D::Error::custom(format!("expression error in {:?}: {}", source.text(), err))
+                })?,
+                source,
+            },
+        })
+    }
+}
+
+type Spans = Vec<(NodeId, (ParseErrorPos, ParseErrorPos))>;
+
+#[derive(Serialize)]
+#[serde(crate = "serde_crate", rename = "BulletML")]
+struct BulletMLRef<'a> {
+    arena: &'a Arena<BulletMLNode>,
+    root: NodeId,
+    bullet_refs: &'a HashMap<String, NodeId>,
+    action_refs: &'a HashMap<String, NodeId>,
+    fire_refs: &'a HashMap<String, NodeId>,
+    spans: Spans,
+    allow_unguarded_recursion: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate", rename = "BulletML")]
+struct BulletMLData {
+    arena: Arena<BulletMLNode>,
+    root: NodeId,
+    bullet_refs: HashMap<String, NodeId>,
+    action_refs: HashMap<String, NodeId>,
+    fire_refs: HashMap<String, NodeId>,
+    spans: Spans,
+    /// Whether the document was parsed or built with unguarded recursion allowed. It is missing
+    /// from the documents serialized before it was introduced.
+    #[serde(default)]
+    allow_unguarded_recursion: bool,
+}
+
+impl Serialize for BulletML {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Spans are keyed by node, which is not a valid map key in all formats, and sorted to
+        // keep the output stable.
+        let mut spans = self
+            .spans
+            .iter()
+            .map(|(id, span)| (*id, *span))
+            .collect::<Spans>();
+        spans.sort_by_key(|(id, _)| *id);
+        // A document can only recurse without waiting if it was allowed to when parsed or built,
+        // so the loaded document is allowed to as well.
+        let allow_unguarded_recursion =
+            analysis::find_unguarded_cycle(&self.arena, &self.action_refs).is_some();
+        BulletMLRef {
+            arena: &self.arena,
+            root: self.root,
+            bullet_refs: &self.bullet_refs,
+            action_refs: &self.action_refs,
+            fire_refs: &self.fire_refs,
+            spans,
+            allow_unguarded_recursion,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BulletML {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let BulletMLData {
+            arena,
+            root,
+            bullet_refs,
+            action_refs,
+            fire_refs,
+            spans,
+            allow_unguarded_recursion,
+        } = BulletMLData::deserialize(deserializer)?;
+
+        // The runner indexes the arena without checking, make sure it cannot panic.
+        match arena.get(root).map(|node| node.get()) {
+            Some(BulletMLNode::BulletML { .. }) => {}
+            _ => return Err(D::Error::custom("root is not a bulletml node")),
+        }
+        check_refs::<D::Error>(&arena, &bullet_refs, "bullet", |node| {
+            matches!(node, BulletMLNode::Bullet(..))
+        })?;
+        check_refs::<D::Error>(&arena, &action_refs, "action", |node| {
+            matches!(node, BulletMLNode::Action(..))
+        })?;
+        check_refs::<D::Error>(&arena, &fire_refs, "fire", |node| {
+            matches!(node, BulletMLNode::Fire(..))
+        })?;
+
+        let bml = BulletML {
+            arena,
+            root,
+            bullet_refs,
+            action_refs,
+            fire_refs,
+            spans: spans.into_iter().collect(),
+        };
+        check_references::<D::Error>(&bml, allow_unguarded_recursion)?;
+        Ok(bml)
+    }
+}
+
+fn check_refs<E: Error>(
+    arena: &Arena<BulletMLNode>,
+    refs: &HashMap<String, NodeId>,
+    kind: &str,
+    is_kind: fn(&BulletMLNode) -> bool,
+) -> Result<(), E> {
+    for (label, id) in refs {
+        match arena.get(*id) {
+            Some(node) if is_kind(node.get()) => {}
+            _ => return Err(E::custom(format!("{} {} is not defined", kind, label))),
+        }
+    }
+    Ok(())
+}
+
+/// Runs the checks of the parser on the references of the whole arena: every reference must point
+/// at an existing label, pass enough parameters, and no action may recurse without waiting unless
+/// `allow_unguarded_recursion` is set.
+fn check_references<E: Error>(bml: &BulletML, allow_unguarded_recursion: bool) -> Result<(), E> {
+    for node in bml.arena.iter().filter(|node| !node.is_removed()) {
+        let (kind, label) = match node.get().match_ref() {
+            Some(reference) => reference,
+            None => continue,
+        };
+        let id = bml
+            .arena
+            .get_node_id(node)
+            .ok_or_else(|| E::custom("node outside of the arena"))?;
+        let labels = match kind {
+            ReferenceKind::Bullet => &bml.bullet_refs,
+            ReferenceKind::Action => &bml.action_refs,
+            ReferenceKind::Fire => &bml.fire_refs,
+        };
+        let target = labels.get(label).ok_or_else(|| {
+            E::custom(format!(
+                "unresolved label {} in element {} at node {}",
+                label, kind, id
+            ))
+        })?;
+        let expected = analysis::max_parameter(&bml.arena, *target);
+        let found = analysis::parameter_count(&bml.arena, id);
+        if found < expected {
+            return Err(E::custom(format!(
+                "label {} in element {} expects {} parameters but {} are passed at node {}",
+                label, kind, expected, found, id
+            )));
+        }
+    }
+    if allow_unguarded_recursion {
+        return Ok(());
+    }
+    if let Some(ref_id) = analysis::find_unguarded_cycle(&bml.arena, &bml.action_refs) {
+        if let Some((kind, label)) = bml.arena[ref_id].get().match_ref() {
+            return Err(E::custom(format!(
+                "label {} in element {} recurses without waiting at node {}",
+                label, kind, ref_id
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::parse::BulletMLParser;
+
+    const DOCUMENT: &str = r##"<?xml version="1.0" ?>
+<bulletml type="vertical">
+    <action label="top">
+        <repeat>
+            <times>2 + $rank * 10</times>
+            <actionRef label="shoot">
+                <param>90</param>
+            </actionRef>
+        </repeat>
+    </action>
+    <action label="shoot">
+        <fire>
+            <direction type="absolute">$1 + $rand</direction>
+            <bullet />
+        </fire>
+        <wait>1</wait>
+    </action>
+</bulletml>"##;
+
+    #[test]
+    fn test_round_trip() {
+        let bml = BulletMLParser::new().parse(DOCUMENT).unwrap();
+        let json = serde_json::to_string(&bml).unwrap();
+        let loaded: BulletML = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.root, bml.root);
+        assert_eq!(loaded.action_refs, bml.action_refs);
+        assert_eq!(loaded.spans, bml.spans);
+        assert_eq!(format!("{:?}", loaded.arena), format!("{:?}", bml.arena),);
+        for (node, loaded_node) in bml.arena.iter().zip(loaded.arena.iter()) {
+            if let (
+                Some(BulletMLExpression::Expr { expr, .. }),
+                Some(BulletMLExpression::Expr {
+                    expr: loaded_expr, ..
+                }),
+            ) = (node.get().expression(), loaded_node.get().expression())
+            {
+                assert_eq!(loaded_expr, expr);
+            }
+        }
+    }
+
+    #[test]
+    fn test_expression() {
+        let bml = BulletMLParser::new().parse(DOCUMENT).unwrap();
+        let times = bml.action_refs["top"]
+            .children(&bml.arena)
+            .next()
+            .unwrap()
+            .children(&bml.arena)
+            .next()
+            .unwrap();
+        let json = serde_json::to_string(bml.arena[times].get()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Times":{"Expr":{"text":"2 + $rank * 10","span":[{"row":5,"col":20},{"row":5,"col":34}]}}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&BulletMLExpression::Const(1.5)).unwrap(),
+            r#"{"Const":1.5}"#
+        );
+    }
+
+    #[test]
+    fn test_invalid_expression() {
+        let bml = BulletMLParser::new().parse(DOCUMENT).unwrap();
+        let json = serde_json::to_string(&bml)
+            .unwrap()
+            .replace("$1 + $rand", "$1 +");
+        let err = serde_json::from_str::<BulletML>(&json).unwrap_err();
+        assert!(err.to_string().starts_with("expression error in \"$1 +\""));
+    }
+
+    #[test]
+    fn test_app_names() {
+        let bml = BulletMLParser::new()
+            .variable("hp")
+            .function("angleTo")
+            .parse(
+                r#"<bulletml><action label="top"><wait>angleTo($hp, 1)</wait></action></bulletml>"#,
+            )
+            .unwrap();
+        let json = serde_json::to_string(&bml).unwrap();
+        assert!(serde_json::from_str::<BulletML>(&json).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_refs() {
+        let bml = BulletMLParser::new().parse(DOCUMENT).unwrap();
+        let mut json = serde_json::to_value(&bml).unwrap();
+        json["action_refs"]["top"] = json["root"].clone();
+        let err = serde_json::from_value::<BulletML>(json.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "action top is not defined");
+
+        json["root"] = json["action_refs"]["shoot"].clone();
+        let err = serde_json::from_value::<BulletML>(json).unwrap_err();
+        assert_eq!(err.to_string(), "root is not a bulletml node");
+    }
+
+    #[test]
+    fn test_invalid_references() {
+        let bml = BulletMLParser::new().parse(DOCUMENT).unwrap();
+        let reference = bml
+            .arena
+            .iter()
+            .find(|node| node.get().match_ref().is_some())
+            .and_then(|node| bml.arena.get_node_id(node))
+            .unwrap();
+        let json = serde_json::to_string(&bml).unwrap();
+
+        let err = serde_json::from_str::<BulletML>(
+            &json.replace(r#""ActionRef":"shoot""#, r#""ActionRef":"gone""#),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "unresolved label gone in element actionRef at node {}",
+                reference
+            )
+        );
+
+        let err = serde_json::from_str::<BulletML>(&json.replace("$1 + $rand", "$2 + $rand"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "label shoot in element actionRef expects 2 parameters but 1 are passed at node {}",
+                reference
+            )
+        );
+
+        let err = serde_json::from_str::<BulletML>(
+            &json.replace(r#"{"Wait":{"Const":1.0}}"#, r#"{"ActionRef":"top"}"#),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("label top in element actionRef recurses without waiting at node "));
+    }
+
+    #[test]
+    fn test_unguarded_recursion() {
+        let doc = r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <wait>$rank * 10 + 1</wait>
+        <actionRef label="top" />
+    </action>
+</bulletml>"##;
+        let bml = BulletMLParser::new()
+            .allow_unguarded_recursion(true)
+            .parse(doc)
+            .unwrap();
+        let json = serde_json::to_string(&bml).unwrap();
+        let loaded = serde_json::from_str::<BulletML>(&json).unwrap();
+        assert_eq!(loaded.action_refs, bml.action_refs);
+        assert_eq!(
+            serde_json::to_string(&loaded).unwrap(),
+            serde_json::to_string(&bml).unwrap()
+        );
+
+        // The flag is only set for documents which need it, and checked when loading.
+        let guarded = serde_json::to_value(BulletMLParser::new().parse(DOCUMENT).unwrap()).unwrap();
+        assert_eq!(guarded["allow_unguarded_recursion"], false);
+        let mut json = serde_json::to_value(&bml).unwrap();
+        json["allow_unguarded_recursion"] = false.into();
+        assert!(serde_json::from_value::<BulletML>(json).is_err());
+    }
+}
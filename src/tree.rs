@@ -1,13 +1,95 @@
+use crate::errors::{ParseErrorPos, ReferenceKind};
+use crate::expr::CompiledExpression;
 use indextree::{Arena, NodeId};
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Clone)]
 pub enum BulletMLExpression {
+    /// A number written in its shortest form, e.g. `1.5`. Numbers written otherwise, e.g. `1.50`,
+    /// are parsed as expressions so that their source is kept.
     Const(f64),
-    Expr(fasteval::ExpressionI),
+    Expr {
+        expr: CompiledExpression,
+        source: ExpressionSource,
+    },
+}
+
+impl BulletMLExpression {
+    /// Gets the original source of the expression unless it is a constant.
+    pub fn source(&self) -> Option<&ExpressionSource> {
+        match self {
+            BulletMLExpression::Const(..) => None,
+            BulletMLExpression::Expr { source, .. } => Some(source),
+        }
+    }
+
+    /// Gets the value of the expression if it is a number or if it depends on no variable, e.g.
+    /// `360 / 16`.
+    pub fn constant(&self) -> Option<f64> {
+        match self {
+            BulletMLExpression::Const(value) => Some(*value),
+            BulletMLExpression::Expr { expr, .. } => expr.constant(),
+        }
+    }
+
+    /// Pre-evaluates the parts of the expression which only depend on `$rank`.
+    pub(crate) fn specialize_for_rank(&mut self, rank: f64) {
+        if let BulletMLExpression::Expr { expr, .. } = self {
+            *expr = expr.specialize_for_rank(rank);
+        }
+    }
+
+    /// Gets the highest parameter (`$1`, `$2`, ...) used by the expression.
+    pub(crate) fn max_parameter(&self) -> usize {
+        match self {
+            BulletMLExpression::Const(..) => 0,
+            BulletMLExpression::Expr { expr, .. } => expr.max_parameter(),
+        }
+    }
+}
+
+impl Debug for BulletMLExpression {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            BulletMLExpression::Const(value) => f.debug_tuple("Const").field(value).finish(),
+            BulletMLExpression::Expr { source, .. } => {
+                f.debug_tuple("Expr").field(&source.text()).finish()
+            }
+        }
+    }
+}
+
+/// Original text of an expression, as written in the document.
+#[derive(Debug, Clone, PartialEq, new)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct ExpressionSource {
+    text: String,
+    span: Option<(ParseErrorPos, ParseErrorPos)>,
+}
+
+impl ExpressionSource {
+    /// Gets the text of the expression, without surrounding whitespaces.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Gets the start and end positions of the expression in the document it comes from, if any.
+    pub fn span(&self) -> Option<(ParseErrorPos, ParseErrorPos)> {
+        self.span
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub enum BulletMLNode {
     BulletML {
         bml_type: Option<BulletMLType>,
@@ -56,15 +138,34 @@ pub enum BulletMLNode {
     FireRef(String),
 
     Param(BulletMLExpression),
+
+    /// Element registered with
+    /// [BulletMLParser::custom_element](parse/struct.BulletMLParser.html#method.custom_element),
+    /// run by [AppRunner::do_custom](trait.AppRunner.html#method.do_custom).
+    Custom {
+        name: String,
+        attributes: Vec<(String, String)>,
+        params: Vec<BulletMLExpression>,
+    },
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub enum BulletMLType {
     Vertical,
     Horizontal,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub enum DirectionType {
     Aim,
     Absolute,
@@ -73,6 +174,11 @@ pub enum DirectionType {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub enum SpeedType {
     Absolute,
     Relative,
@@ -80,6 +186,11 @@ pub enum SpeedType {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub enum HVType {
     Absolute,
     Relative,
@@ -109,49 +220,104 @@ impl BulletMLNode {
         }
     }
 
-    pub fn match_direction(&self) -> Option<(Option<DirectionType>, BulletMLExpression)> {
+    pub fn match_ref(&self) -> Option<(ReferenceKind, &str)> {
+        match self {
+            BulletMLNode::BulletRef(label) => Some((ReferenceKind::Bullet, label)),
+            BulletMLNode::ActionRef(label) => Some((ReferenceKind::Action, label)),
+            BulletMLNode::FireRef(label) => Some((ReferenceKind::Fire, label)),
+            _ => None,
+        }
+    }
+
+    pub fn expression(&self) -> Option<&BulletMLExpression> {
+        match self {
+            BulletMLNode::Wait(expr)
+            | BulletMLNode::Direction { dir: expr, .. }
+            | BulletMLNode::Speed { spd: expr, .. }
+            | BulletMLNode::Horizontal { h: expr, .. }
+            | BulletMLNode::Vertical { v: expr, .. }
+            | BulletMLNode::Term(expr)
+            | BulletMLNode::Times(expr)
+            | BulletMLNode::Param(expr) => Some(expr),
+            _ => None,
+        }
+    }
+
+    /// Iterates over all the expressions of the node, i.e. its [expression](#method.expression)
+    /// or the parameters of a custom element.
+    pub fn expressions(&self) -> impl Iterator<Item = &BulletMLExpression> {
+        let params: &[BulletMLExpression] = match self {
+            BulletMLNode::Custom { params, .. } => params,
+            _ => &[],
+        };
+        self.expression().into_iter().chain(params)
+    }
+
+    pub(crate) fn expressions_mut(&mut self) -> impl Iterator<Item = &mut BulletMLExpression> {
+        let (expr, params): (_, &mut [BulletMLExpression]) = match self {
+            BulletMLNode::Custom { params, .. } => (None, params),
+            node => (node.expression_mut(), &mut []),
+        };
+        expr.into_iter().chain(params)
+    }
+
+    fn expression_mut(&mut self) -> Option<&mut BulletMLExpression> {
+        match self {
+            BulletMLNode::Wait(expr)
+            | BulletMLNode::Direction { dir: expr, .. }
+            | BulletMLNode::Speed { spd: expr, .. }
+            | BulletMLNode::Horizontal { h: expr, .. }
+            | BulletMLNode::Vertical { v: expr, .. }
+            | BulletMLNode::Term(expr)
+            | BulletMLNode::Times(expr)
+            | BulletMLNode::Param(expr) => Some(expr),
+            _ => None,
+        }
+    }
+
+    pub fn match_direction(&self) -> Option<(Option<DirectionType>, &BulletMLExpression)> {
         if let BulletMLNode::Direction { dir_type, dir } = self {
-            Some((*dir_type, *dir))
+            Some((*dir_type, dir))
         } else {
             None
         }
     }
 
-    pub fn match_speed(&self) -> Option<(Option<SpeedType>, BulletMLExpression)> {
+    pub fn match_speed(&self) -> Option<(Option<SpeedType>, &BulletMLExpression)> {
         if let BulletMLNode::Speed { spd_type, spd } = self {
-            Some((*spd_type, *spd))
+            Some((*spd_type, spd))
         } else {
             None
         }
     }
 
-    pub fn match_horizontal(&self) -> Option<(HVType, BulletMLExpression)> {
+    pub fn match_horizontal(&self) -> Option<(HVType, &BulletMLExpression)> {
         if let BulletMLNode::Horizontal { h_type, h } = self {
-            Some((*h_type, *h))
+            Some((*h_type, h))
         } else {
             None
         }
     }
 
-    pub fn match_vertical(&self) -> Option<(HVType, BulletMLExpression)> {
+    pub fn match_vertical(&self) -> Option<(HVType, &BulletMLExpression)> {
         if let BulletMLNode::Vertical { v_type, v } = self {
-            Some((*v_type, *v))
+            Some((*v_type, v))
         } else {
             None
         }
     }
 
-    pub fn match_term(&self) -> Option<BulletMLExpression> {
+    pub fn match_term(&self) -> Option<&BulletMLExpression> {
         if let BulletMLNode::Term(term) = self {
-            Some(*term)
+            Some(term)
         } else {
             None
         }
     }
 
-    pub fn match_times(&self) -> Option<BulletMLExpression> {
+    pub fn match_times(&self) -> Option<&BulletMLExpression> {
         if let BulletMLNode::Times(times) = self {
-            Some(*times)
+            Some(times)
         } else {
             None
         }
@@ -159,6 +325,12 @@ impl BulletMLNode {
 }
 
 /// Parsed representation of a BulletML document, ready to use by a [Runner](struct.Runner.html).
+///
+/// With the `serde` feature, the document can be serialized and deserialized. Expressions are
+/// serialized as their source text and compiled again on load. The references of a loaded document
+/// are checked like those of a parsed one, so that a damaged document cannot make the runner panic
+/// or loop forever. Recursion without waiting is only accepted in documents serialized from a
+/// document parsed or built with it allowed.
 #[derive(Debug)]
 pub struct BulletML {
     pub arena: Arena<BulletMLNode>,
@@ -166,10 +338,29 @@ pub struct BulletML {
     pub bullet_refs: HashMap<String, NodeId>,
     pub action_refs: HashMap<String, NodeId>,
     pub fire_refs: HashMap<String, NodeId>,
-    pub expr_slab: fasteval::Slab,
+    /// Start and end positions, in the source document, of the XML element of each node. The
+    /// positions of imported nodes refer to the imported document.
+    pub spans: HashMap<NodeId, (ParseErrorPos, ParseErrorPos)>,
 }
 
 impl BulletML {
+    /// Returns the start and end positions of the XML element `id` was parsed from, if any.
+    pub fn node_span(&self, id: NodeId) -> Option<(ParseErrorPos, ParseErrorPos)> {
+        self.spans.get(&id).copied()
+    }
+
+    /// Pre-evaluates the expressions, or the parts of them, which only depend on `$rank` for a
+    /// fixed `rank`, so that the runners skip evaluating them.
+    /// [AppRunner::get_rank](trait.AppRunner.html#tymethod.get_rank) is no longer called for
+    /// those expressions. Their source is kept as is.
+    pub fn specialize_for_rank(&mut self, rank: f64) {
+        for node in self.arena.iter_mut() {
+            for expr in node.get_mut().expressions_mut() {
+                expr.specialize_for_rank(rank);
+            }
+        }
+    }
+
     pub(crate) fn get_type(&self) -> Option<BulletMLType> {
         let root_node = &self.arena[self.root];
         if let BulletMLNode::BulletML { bml_type } = root_node.get() {
@@ -0,0 +1,737 @@
+use crate::errors::RunError;
+use crate::runner::{AppRunner, Runner, RunnerData, State};
+use crate::tree::BulletML;
+use std::sync::Arc;
+
+/// Application hooks of a [BulletWorld](struct.BulletWorld.html).
+pub trait WorldHooks {
+    /// Gets the position the bullets aim at, usually the one of the player.
+    fn aim_target(&self) -> (f64, f64);
+    /// Gets the BulletML "rank", a value between 0 and 1 indicating the level of difficulty.
+    fn rank(&self) -> f64;
+    /// Tells whether `bullet` must be removed, e.g. because it is out of bounds. Vanished bullets
+    /// are removed anyway.
+    fn should_remove(&mut self, _id: BulletId, _bullet: &Bullet) -> bool {
+        false
+    }
+}
+
+/// Handle of a bullet of a [BulletWorld](struct.BulletWorld.html).
+///
+/// Handles are not reused: once the bullet is removed, its handle refers to no bullet even if
+/// another bullet takes its place. A place which has held as many bullets as its generation counter
+/// can count is retired rather than reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BulletId {
+    index: u32,
+    generation: u32,
+}
+
+/// Bullet of a [BulletWorld](struct.BulletWorld.html), which moves according to its direction,
+/// its speed and its acceleration, and possibly runs BulletML actions.
+///
+/// Directions are in degrees, 0 pointing up and 90 pointing right. The Y axis points down as on
+/// screen. Speeds are in units per step.
+pub struct Bullet {
+    runner: Runner<Motion>,
+    bml: Option<Arc<BulletML>>,
+}
+
+impl Bullet {
+    /// Gets the position of the bullet.
+    pub fn position(&self) -> (f64, f64) {
+        (self.runner.x, self.runner.y)
+    }
+
+    /// Moves the bullet to `(x, y)`, e.g. to make an emitter follow an enemy.
+    pub fn set_position(&mut self, x: f64, y: f64) {
+        self.runner.x = x;
+        self.runner.y = y;
+    }
+
+    /// Gets the direction of the bullet.
+    pub fn direction(&self) -> f64 {
+        self.runner.direction
+    }
+
+    /// Gets the speed of the bullet along its direction.
+    pub fn speed(&self) -> f64 {
+        self.runner.speed
+    }
+
+    /// Gets the distance covered by the bullet at each step, along X and Y. That is the sum of
+    /// the movement along its direction and of the speeds set by `<accel>`.
+    pub fn velocity(&self) -> (f64, f64) {
+        self.runner.velocity()
+    }
+
+    /// Tells whether the bullet still runs BulletML actions.
+    pub fn is_running(&self) -> bool {
+        self.bml.is_some() && !self.runner.is_end()
+    }
+}
+
+struct Slot {
+    generation: u32,
+    bullet: Option<Bullet>,
+}
+
+/// Manager of all the bullets run from BulletML documents: it runs their actions, moves them,
+/// creates the bullets they fire and removes the ones which vanish.
+///
+/// The application only provides the [hooks](trait.WorldHooks.html) which depend on the game.
+///
+/// ```
+/// use bulletml::parse::BulletMLParser;
+/// use bulletml::world::{BulletWorld, WorldHooks};
+/// use std::sync::Arc;
+///
+/// struct Game {
+///     player: (f64, f64),
+/// }
+///
+/// impl WorldHooks for Game {
+///     fn aim_target(&self) -> (f64, f64) {
+///         self.player
+///     }
+///
+///     fn rank(&self) -> f64 {
+///         0.5
+///     }
+/// }
+///
+/// let bml = BulletMLParser::new()
+///     .parse(
+///         r#"<bulletml>
+///             <action label="top">
+///                 <fire><speed>2</speed><bullet /></fire>
+///             </action>
+///         </bulletml>"#,
+///     )
+///     .unwrap();
+/// let mut world = BulletWorld::new(Game { player: (0., -100.) });
+/// let enemy = world.spawn(Arc::new(bml), 0., 0.);
+/// world.step().unwrap();
+/// world.step().unwrap();
+/// let (_, bullet) = world.bullets().find(|(id, _)| *id != enemy).unwrap();
+/// assert_eq!(bullet.position(), (0., -2.));
+/// ```
+pub struct BulletWorld<H> {
+    hooks: H,
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    len: usize,
+    turn: u32,
+    rand: u64,
+    default_speed: f64,
+}
+
+impl<H: WorldHooks> BulletWorld<H> {
+    /// Creates an empty world.
+    pub fn new(hooks: H) -> Self {
+        BulletWorld {
+            hooks,
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            turn: 0,
+            rand: 0x2545_f491_4f6c_dd1d,
+            default_speed: 1.,
+        }
+    }
+
+    /// Seeds the random number generator used for `$rand`. Different seeds give different
+    /// sequences.
+    pub fn seed(mut self, seed: u64) -> Self {
+        // One step of splitmix64, which maps different seeds to different states.
+        let mut rand = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        rand = (rand ^ (rand >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        rand = (rand ^ (rand >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        rand ^= rand >> 31;
+        // The generator must not start from 0.
+        self.rand = if rand == 0 {
+            0x2545_f491_4f6c_dd1d
+        } else {
+            rand
+        };
+        self
+    }
+
+    /// Sets the speed of the bullets fired without `<speed>`. It is 1 by default.
+    pub fn default_speed(mut self, speed: f64) -> Self {
+        self.default_speed = speed;
+        self
+    }
+
+    /// Gets the hooks.
+    pub fn hooks(&self) -> &H {
+        &self.hooks
+    }
+
+    /// Gets the hooks, e.g. to move the target.
+    pub fn hooks_mut(&mut self) -> &mut H {
+        &mut self.hooks
+    }
+
+    /// Gets the number of steps run so far.
+    pub fn turn(&self) -> u32 {
+        self.turn
+    }
+
+    /// Adds a still bullet at `(x, y)` running all the "top" actions of `bml`, e.g. an enemy.
+    pub fn spawn(&mut self, bml: Arc<BulletML>, x: f64, y: f64) -> BulletId {
+        let runner = Runner::new(self.motion(x, y, 0., 0.), &bml);
+        self.insert(Bullet {
+            runner,
+            bml: Some(bml),
+        })
+    }
+
+    /// Adds a still bullet at `(x, y)` running the action labelled `label` of `bml` with the
+    /// parameters `params`. See [Runner::new_for_action](../struct.Runner.html#method.new_for_action).
+    pub fn spawn_action(
+        &mut self,
+        bml: Arc<BulletML>,
+        label: &str,
+        params: Vec<f64>,
+        x: f64,
+        y: f64,
+    ) -> Result<BulletId, RunError> {
+        let runner = Runner::new_for_action(self.motion(x, y, 0., 0.), &bml, label, params)?;
+        Ok(self.insert(Bullet {
+            runner,
+            bml: Some(bml),
+        }))
+    }
+
+    /// Gets the bullet `id` unless it has been removed.
+    pub fn get(&self, id: BulletId) -> Option<&Bullet> {
+        self.slots
+            .get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.bullet.as_ref())
+    }
+
+    /// Gets the bullet `id` unless it has been removed.
+    pub fn get_mut(&mut self, id: BulletId) -> Option<&mut Bullet> {
+        self.slots
+            .get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.bullet.as_mut())
+    }
+
+    /// Removes the bullet `id`, e.g. when it hits the player. Returns the bullet unless it had
+    /// been removed already.
+    pub fn remove(&mut self, id: BulletId) -> Option<Bullet> {
+        let slot = self
+            .slots
+            .get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)?;
+        let bullet = slot.bullet.take()?;
+        // Wrapping around would make old handles refer to new bullets.
+        if let Some(generation) = slot.generation.checked_add(1) {
+            slot.generation = generation;
+            self.free.push(id.index);
+        }
+        self.len -= 1;
+        Some(bullet)
+    }
+
+    /// Iterates over all the bullets.
+    pub fn bullets(&self) -> impl Iterator<Item = (BulletId, &Bullet)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.bullet.as_ref().map(|bullet| {
+                (
+                    BulletId {
+                        index: index as u32,
+                        generation: slot.generation,
+                    },
+                    bullet,
+                )
+            })
+        })
+    }
+
+    /// Gets the number of bullets.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Tells whether there is no bullet.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Runs one step: runs the actions of the bullets, moves them, removes the ones which vanish
+    /// or which [WorldHooks::should_remove](trait.WorldHooks.html#method.should_remove) tells
+    /// to remove, then adds the bullets they fire.
+    ///
+    /// If the actions of some bullet cannot be run, they are ended, the bullet keeps moving and
+    /// the first such error is returned once the step is complete.
+    pub fn step(&mut self) -> Result<(), RunError> {
+        let mut result = Ok(());
+        let mut fired = Vec::new();
+        for slot in &mut self.slots {
+            let bullet = match &mut slot.bullet {
+                Some(bullet) => bullet,
+                None => continue,
+            };
+            if let Some(bml) = &bullet.bml {
+                if !bullet.runner.is_end() {
+                    let mut data = WorldData {
+                        bml,
+                        aim_target: self.hooks.aim_target(),
+                        rank: self.hooks.rank(),
+                        turn: self.turn,
+                        rand: &mut self.rand,
+                        fired: &mut fired,
+                    };
+                    let res = bullet.runner.try_run(&mut RunnerData {
+                        bml,
+                        data: &mut data,
+                    });
+                    if result.is_ok() {
+                        result = res;
+                    }
+                }
+            }
+            let (vx, vy) = bullet.runner.velocity();
+            bullet.runner.x += vx;
+            bullet.runner.y += vy;
+        }
+
+        for index in 0..self.slots.len() {
+            let slot = &self.slots[index];
+            let id = BulletId {
+                index: index as u32,
+                generation: slot.generation,
+            };
+            let remove = match &slot.bullet {
+                Some(bullet) => bullet.runner.vanished || self.hooks.should_remove(id, bullet),
+                None => false,
+            };
+            if remove {
+                self.remove(id);
+            }
+        }
+
+        for fired in fired {
+            let motion = self.motion(fired.x, fired.y, fired.direction, fired.speed);
+            let bullet = match fired.state {
+                Some((state, bml)) => Bullet {
+                    runner: Runner::new_from_state(motion, state),
+                    bml: Some(bml),
+                },
+                None => Bullet {
+                    runner: Runner::idle(motion),
+                    bml: None,
+                },
+            };
+            self.insert(bullet);
+        }
+
+        self.turn += 1;
+        result
+    }
+
+    fn motion(&self, x: f64, y: f64, direction: f64, speed: f64) -> Motion {
+        Motion {
+            x,
+            y,
+            direction,
+            speed,
+            accel_x: 0.,
+            accel_y: 0.,
+            default_speed: self.default_speed,
+            vanished: false,
+        }
+    }
+
+    fn insert(&mut self, bullet: Bullet) -> BulletId {
+        self.len += 1;
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.bullet = Some(bullet);
+            BulletId {
+                index,
+                generation: slot.generation,
+            }
+        } else {
+            self.slots.push(Slot {
+                generation: 0,
+                bullet: Some(bullet),
+            });
+            BulletId {
+                index: (self.slots.len() - 1) as u32,
+                generation: 0,
+            }
+        }
+    }
+}
+
+/// Kinematics of a bullet, which is also the application runner of its actions.
+struct Motion {
+    x: f64,
+    y: f64,
+    direction: f64,
+    speed: f64,
+    accel_x: f64,
+    accel_y: f64,
+    default_speed: f64,
+    vanished: bool,
+}
+
+impl Motion {
+    fn velocity(&self) -> (f64, f64) {
+        let (sin, cos) = self.direction.to_radians().sin_cos();
+        (
+            self.speed * sin + self.accel_x,
+            -self.speed * cos + self.accel_y,
+        )
+    }
+}
+
+/// Bullet fired during a step, added at its end.
+struct Fired {
+    x: f64,
+    y: f64,
+    direction: f64,
+    speed: f64,
+    state: Option<(State, Arc<BulletML>)>,
+}
+
+struct WorldData<'a> {
+    bml: &'a Arc<BulletML>,
+    aim_target: (f64, f64),
+    rank: f64,
+    turn: u32,
+    rand: &'a mut u64,
+    fired: &'a mut Vec<Fired>,
+}
+
+impl<'a> AppRunner<WorldData<'a>> for Motion {
+    fn get_bullet_direction(&self, _data: &WorldData<'a>) -> f64 {
+        self.direction
+    }
+
+    fn get_aim_direction(&self, data: &WorldData<'a>) -> f64 {
+        let (x, y) = data.aim_target;
+        (x - self.x).atan2(self.y - y).to_degrees()
+    }
+
+    fn get_bullet_speed(&self, _data: &WorldData<'a>) -> f64 {
+        self.speed
+    }
+
+    fn get_default_speed(&self) -> f64 {
+        self.default_speed
+    }
+
+    fn get_rank(&self, data: &WorldData<'a>) -> f64 {
+        data.rank
+    }
+
+    fn create_simple_bullet(&mut self, data: &mut WorldData<'a>, direction: f64, speed: f64) {
+        data.fired.push(Fired {
+            x: self.x,
+            y: self.y,
+            direction,
+            speed,
+            state: None,
+        });
+    }
+
+    fn create_bullet(
+        &mut self,
+        data: &mut WorldData<'a>,
+        state: State,
+        direction: f64,
+        speed: f64,
+    ) {
+        data.fired.push(Fired {
+            x: self.x,
+            y: self.y,
+            direction,
+            speed,
+            state: Some((state, data.bml.clone())),
+        });
+    }
+
+    fn get_turn(&self, data: &WorldData<'a>) -> u32 {
+        data.turn
+    }
+
+    fn do_vanish(&mut self, _data: &mut WorldData<'a>) {
+        self.vanished = true;
+    }
+
+    fn do_change_direction(&mut self, _data: &mut WorldData<'a>, direction: f64) {
+        self.direction = direction;
+    }
+
+    fn do_change_speed(&mut self, _data: &mut WorldData<'a>, speed: f64) {
+        self.speed = speed;
+    }
+
+    fn do_accel_x(&mut self, accel_x: f64) {
+        self.accel_x = accel_x;
+    }
+
+    fn do_accel_y(&mut self, accel_y: f64) {
+        self.accel_y = accel_y;
+    }
+
+    fn get_bullet_speed_x(&self) -> f64 {
+        self.accel_x
+    }
+
+    fn get_bullet_speed_y(&self) -> f64 {
+        self.accel_y
+    }
+
+    fn get_rand(&self, data: &mut WorldData<'a>) -> f64 {
+        // xorshift64*
+        let mut rand = *data.rand;
+        rand ^= rand >> 12;
+        rand ^= rand << 25;
+        rand ^= rand >> 27;
+        *data.rand = rand;
+        (rand.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use super::*;
+    use crate::parse::BulletMLParser;
+
+    struct TestHooks {
+        target: (f64, f64),
+        removed: Vec<BulletId>,
+    }
+
+    impl WorldHooks for TestHooks {
+        fn aim_target(&self) -> (f64, f64) {
+            self.target
+        }
+
+        fn rank(&self) -> f64 {
+            0.5
+        }
+
+        fn should_remove(&mut self, id: BulletId, bullet: &Bullet) -> bool {
+            let (_, y) = bullet.position();
+            if y > 10. {
+                self.removed.push(id);
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    fn world(doc: &str) -> (BulletWorld<TestHooks>, BulletId) {
+        let bml = BulletMLParser::new().parse(doc).unwrap();
+        let mut world = BulletWorld::new(TestHooks {
+            target: (0., 10.),
+            removed: Vec::new(),
+        });
+        let emitter = world.spawn(Arc::new(bml), 0., 0.);
+        (world, emitter)
+    }
+
+    fn assert_close((x, y): (f64, f64), (expected_x, expected_y): (f64, f64)) {
+        assert!(
+            (x - expected_x).abs() < 1e-9 && (y - expected_y).abs() < 1e-9,
+            "({}, {}) != ({}, {})",
+            x,
+            y,
+            expected_x,
+            expected_y
+        );
+    }
+
+    #[test]
+    fn test_fire_and_move() {
+        let (mut world, emitter) = world(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <repeat>
+        <times>3</times>
+        <action>
+            <fire>
+                <direction type="absolute">90</direction>
+                <speed>$rank * 2</speed>
+                <bullet />
+            </fire>
+            <wait>1</wait>
+        </action>
+    </repeat>
+    <fire>
+        <bullet />
+    </fire>
+</action>
+</bulletml>"##,
+        );
+        for _ in 0..4 {
+            world.step().unwrap();
+        }
+        assert_eq!(world.turn(), 4);
+        assert_eq!(world.len(), 5);
+        let bullets = world
+            .bullets()
+            .filter(|(id, _)| *id != emitter)
+            .map(|(_, bullet)| bullet)
+            .collect::<Vec<_>>();
+        // Bullets fired during a step move from the next one.
+        assert_close(bullets[0].position(), (3., 0.));
+        assert_close(bullets[1].position(), (2., 0.));
+        assert_close(bullets[2].position(), (1., 0.));
+        // Aimed at the target with the default speed.
+        assert_close(bullets[3].position(), (0., 0.));
+        assert_close(bullets[3].velocity(), (0., 1.));
+        assert!(bullets.iter().all(|bullet| !bullet.is_running()));
+        assert!(world.get(emitter).unwrap().is_running());
+        world.step().unwrap();
+        assert!(!world.get(emitter).unwrap().is_running());
+    }
+
+    #[test]
+    fn test_bullet_actions() {
+        let (mut world, emitter) = world(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <fire>
+        <direction type="absolute">0</direction>
+        <speed>2</speed>
+        <bullet>
+            <action>
+                <changeSpeed>
+                    <speed>0</speed>
+                    <term>2</term>
+                </changeSpeed>
+                <accel>
+                    <horizontal>1</horizontal>
+                    <term>1</term>
+                </accel>
+                <wait>3</wait>
+                <vanish />
+            </action>
+        </bullet>
+    </fire>
+</action>
+</bulletml>"##,
+        );
+        world.step().unwrap();
+        let bullet = world
+            .bullets()
+            .map(|(id, _)| id)
+            .find(|id| *id != emitter)
+            .unwrap();
+        let mut positions = Vec::new();
+        for _ in 0..3 {
+            world.step().unwrap();
+            positions.push(world.get(bullet).unwrap().position());
+        }
+        // The changes start with the step after the one which runs their element.
+        assert_close(positions[0], (0., -2.));
+        assert_close(positions[1], (1., -3.));
+        assert_close(positions[2], (2., -3.));
+        assert!(world.get(bullet).unwrap().is_running());
+        world.step().unwrap();
+        assert!(world.get(bullet).is_none());
+        assert!(world.hooks().removed.is_empty());
+    }
+
+    #[test]
+    fn test_should_remove() {
+        let (mut world, emitter) = world(
+            r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <fire>
+        <speed>5</speed>
+        <bullet />
+    </fire>
+</action>
+</bulletml>"##,
+        );
+        world.get_mut(emitter).unwrap().set_position(0., -10.);
+        world.hooks_mut().target = (0., 0.);
+        world.step().unwrap();
+        assert_eq!(world.len(), 2);
+        for _ in 0..4 {
+            world.step().unwrap();
+        }
+        assert_eq!(world.len(), 2);
+        world.step().unwrap();
+        assert_eq!(world.len(), 1);
+        assert_eq!(world.hooks().removed.len(), 1);
+        assert!(world.get(world.hooks().removed[0]).is_none());
+    }
+
+    #[test]
+    fn test_handles() {
+        let (mut world, emitter) = world(r##"<bulletml><action label="top" /></bulletml>"##);
+        assert!(world.remove(emitter).is_some());
+        assert!(world.remove(emitter).is_none());
+        assert!(world.is_empty());
+        let bml = Arc::new(BulletMLParser::new().parse("<bulletml />").unwrap());
+        let other = world.spawn(bml.clone(), 1., 2.);
+        assert_ne!(other, emitter);
+        assert!(world.get(emitter).is_none());
+        assert_close(world.get(other).unwrap().position(), (1., 2.));
+        assert_matches!(
+            world.spawn_action(bml.clone(), "top", Vec::new(), 0., 0.),
+            Err(RunError::UnknownAction { .. })
+        );
+        assert_eq!(world.len(), 1);
+
+        // A slot is retired rather than wrapping around its generation.
+        world.slots[other.index as usize].generation = u32::MAX;
+        let last = BulletId {
+            generation: u32::MAX,
+            ..other
+        };
+        assert!(world.remove(last).is_some());
+        let next = world.spawn(bml, 0., 0.);
+        assert_ne!(next.index, last.index);
+        assert!(world.get(last).is_none());
+        assert!(world.get(next).is_some());
+    }
+
+    #[test]
+    fn test_rand() {
+        let doc = r##"<?xml version="1.0" ?>
+<bulletml>
+<action label="top">
+    <repeat>
+        <times>10</times>
+        <action>
+            <fire>
+                <direction type="absolute">$rand * 360</direction>
+                <bullet />
+            </fire>
+        </action>
+    </repeat>
+</action>
+</bulletml>"##;
+        let directions = |seed| {
+            let (world, _) = world(doc);
+            let mut world = world.seed(seed);
+            world.step().unwrap();
+            world
+                .bullets()
+                .map(|(_, bullet)| bullet.direction())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(directions(1), directions(1));
+        assert_ne!(directions(1), directions(2));
+        assert_ne!(directions(2), directions(3));
+        assert_ne!(directions(0), directions(1));
+        assert!(directions(3)[1..].iter().all(|d| (0. ..360.).contains(d)));
+    }
+}
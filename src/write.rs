@@ -0,0 +1,359 @@
+use crate::tree::{
+    BulletML, BulletMLExpression, BulletMLNode, BulletMLType, DirectionType, HVType, SpeedType,
+};
+use indextree::NodeId;
+
+impl BulletML {
+    /// Serializes this document to a BulletML XML document which can be parsed again with
+    /// [BulletMLParser](parse/struct.BulletMLParser.html).
+    ///
+    /// Labels and types are preserved and expressions are written with their original text.
+    pub fn to_xml_string(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" ?>\n");
+        self.write_node(&mut xml, self.root, 0);
+        xml
+    }
+
+    fn write_node(&self, xml: &mut String, id: NodeId, depth: usize) {
+        let node = self.arena[id].get();
+        let (name, attributes, expr) = Self::xml_parts(node);
+        for _ in 0..depth {
+            xml.push_str("    ");
+        }
+        xml.push('<');
+        xml.push_str(name);
+        for (attribute, value) in attributes {
+            xml.push(' ');
+            xml.push_str(attribute);
+            xml.push_str("=\"");
+            escape_into(xml, value);
+            xml.push('"');
+        }
+        let params: &[BulletMLExpression] = match node {
+            BulletMLNode::Custom { params, .. } => params,
+            _ => &[],
+        };
+        if let Some(expr) = expr {
+            xml.push('>');
+            write_expression(xml, expr);
+        } else if !params.is_empty() || id.children(&self.arena).next().is_some() {
+            xml.push_str(">\n");
+            for param in params {
+                for _ in 0..=depth {
+                    xml.push_str("    ");
+                }
+                xml.push_str("<param>");
+                write_expression(xml, param);
+                xml.push_str("</param>\n");
+            }
+            for child in id.children(&self.arena) {
+                self.write_node(xml, child, depth + 1);
+            }
+            for _ in 0..depth {
+                xml.push_str("    ");
+            }
+        } else {
+            xml.push_str(" />\n");
+            return;
+        }
+        xml.push_str("</");
+        xml.push_str(name);
+        xml.push_str(">\n");
+    }
+
+    pub(crate) fn xml_parts(
+        node: &BulletMLNode,
+    ) -> (&str, Vec<(&str, &str)>, Option<&BulletMLExpression>) {
+        match node {
+            BulletMLNode::BulletML { bml_type } => (
+                "bulletml",
+                type_attribute(bml_type.map(bml_type_name)),
+                None,
+            ),
+            BulletMLNode::Bullet(label) => ("bullet", label_attribute(label), None),
+            BulletMLNode::Action(label) => ("action", label_attribute(label), None),
+            BulletMLNode::Fire(label) => ("fire", label_attribute(label), None),
+            BulletMLNode::ChangeDirection => ("changeDirection", Vec::new(), None),
+            BulletMLNode::ChangeSpeed => ("changeSpeed", Vec::new(), None),
+            BulletMLNode::Accel => ("accel", Vec::new(), None),
+            BulletMLNode::Wait(expr) => ("wait", Vec::new(), Some(expr)),
+            BulletMLNode::Vanish => ("vanish", Vec::new(), None),
+            BulletMLNode::Repeat => ("repeat", Vec::new(), None),
+            BulletMLNode::Direction { dir_type, dir } => (
+                "direction",
+                type_attribute(dir_type.map(direction_type_name)),
+                Some(dir),
+            ),
+            BulletMLNode::Speed { spd_type, spd } => (
+                "speed",
+                type_attribute(spd_type.map(speed_type_name)),
+                Some(spd),
+            ),
+            BulletMLNode::Horizontal { h_type, h } => {
+                ("horizontal", type_attribute(hv_type_name(*h_type)), Some(h))
+            }
+            BulletMLNode::Vertical { v_type, v } => {
+                ("vertical", type_attribute(hv_type_name(*v_type)), Some(v))
+            }
+            BulletMLNode::Term(expr) => ("term", Vec::new(), Some(expr)),
+            BulletMLNode::Times(expr) => ("times", Vec::new(), Some(expr)),
+            BulletMLNode::BulletRef(label) => ("bulletRef", vec![("label", label.as_str())], None),
+            BulletMLNode::ActionRef(label) => ("actionRef", vec![("label", label.as_str())], None),
+            BulletMLNode::FireRef(label) => ("fireRef", vec![("label", label.as_str())], None),
+            BulletMLNode::Param(expr) => ("param", Vec::new(), Some(expr)),
+            BulletMLNode::Custom {
+                name, attributes, ..
+            } => (
+                name,
+                attributes
+                    .iter()
+                    .map(|(attribute, value)| (attribute.as_str(), value.as_str()))
+                    .collect(),
+                None,
+            ),
+        }
+    }
+}
+
+fn write_expression(xml: &mut String, expr: &BulletMLExpression) {
+    match expr {
+        BulletMLExpression::Const(value) => xml.push_str(&value.to_string()),
+        BulletMLExpression::Expr { source, .. } => escape_into(xml, source.text()),
+    }
+}
+
+fn label_attribute(label: &Option<String>) -> Vec<(&'static str, &str)> {
+    label
+        .as_ref()
+        .map(|label| vec![("label", label.as_str())])
+        .unwrap_or_default()
+}
+
+fn type_attribute(type_name: Option<&'static str>) -> Vec<(&'static str, &'static str)> {
+    type_name
+        .map(|type_name| vec![("type", type_name)])
+        .unwrap_or_default()
+}
+
+fn bml_type_name(bml_type: BulletMLType) -> &'static str {
+    match bml_type {
+        BulletMLType::Vertical => "vertical",
+        BulletMLType::Horizontal => "horizontal",
+    }
+}
+
+fn direction_type_name(dir_type: DirectionType) -> &'static str {
+    match dir_type {
+        DirectionType::Aim => "aim",
+        DirectionType::Absolute => "absolute",
+        DirectionType::Relative => "relative",
+        DirectionType::Sequence => "sequence",
+    }
+}
+
+fn speed_type_name(spd_type: SpeedType) -> &'static str {
+    match spd_type {
+        SpeedType::Absolute => "absolute",
+        SpeedType::Relative => "relative",
+        SpeedType::Sequence => "sequence",
+    }
+}
+
+fn hv_type_name(hv_type: HVType) -> Option<&'static str> {
+    match hv_type {
+        // Absolute is the default, the attribute is omitted.
+        HVType::Absolute => None,
+        HVType::Relative => Some("relative"),
+        HVType::Sequence => Some("sequence"),
+    }
+}
+
+fn escape_into(xml: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => xml.push_str("&amp;"),
+            '<' => xml.push_str("&lt;"),
+            '>' => xml.push_str("&gt;"),
+            '"' => xml.push_str("&quot;"),
+            '\'' => xml.push_str("&apos;"),
+            c => xml.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use crate::parse::BulletMLParser;
+
+    #[test]
+    fn test_to_xml_string() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml type="horizontal">
+<action label="top">
+    <fire>
+        <direction type="aim">$rand * 10</direction>
+        <speed>1.5</speed>
+        <bulletRef label="b&lt;1&gt;">
+            <param>360 / 16</param>
+        </bulletRef>
+    </fire>
+    <wait>10</wait>
+</action>
+<bullet label="b&lt;1&gt;">
+    <action>
+        <accel>
+            <horizontal>$1</horizontal>
+            <vertical type="sequence">1 &lt; 2</vertical>
+            <term>1</term>
+        </accel>
+        <vanish />
+    </action>
+</bullet>
+</bulletml>"##,
+            )
+            .unwrap();
+        assert_eq!(
+            bml.to_xml_string(),
+            r##"<?xml version="1.0" ?>
+<bulletml type="horizontal">
+    <action label="top">
+        <fire>
+            <direction type="aim">$rand * 10</direction>
+            <speed>1.5</speed>
+            <bulletRef label="b&lt;1&gt;">
+                <param>360 / 16</param>
+            </bulletRef>
+        </fire>
+        <wait>10</wait>
+    </action>
+    <bullet label="b&lt;1&gt;">
+        <action>
+            <accel>
+                <horizontal>$1</horizontal>
+                <vertical type="sequence">1 &lt; 2</vertical>
+                <term>1</term>
+            </accel>
+            <vanish />
+        </action>
+    </bullet>
+</bulletml>
+"##
+        );
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <changeSpeed>
+            <speed> 0.10 </speed>
+            <term>1e3</term>
+        </changeSpeed>
+        <wait>1.50</wait>
+        <wait>1.5</wait>
+    </action>
+</bulletml>"##,
+            )
+            .unwrap();
+        let expressions = bml.action_refs["top"]
+            .descendants(&bml.arena)
+            .filter_map(|id| bml.arena[id].get().expression())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            expressions
+                .iter()
+                .map(|expr| expr.constant())
+                .collect::<Vec<_>>(),
+            vec![Some(0.1), Some(1000.), Some(1.5), Some(1.5)]
+        );
+        let xml = bml.to_xml_string();
+        assert!(xml.contains("<speed>0.10</speed>"));
+        assert!(xml.contains("<term>1e3</term>"));
+        assert!(xml.contains("<wait>1.50</wait>"));
+        assert!(xml.contains("<wait>1.5</wait>"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bml = BulletMLParser::new()
+            .parse(
+                r##"<?xml version="1.0" ?>
+<bulletml type="vertical">
+    <action label="top">
+        <repeat>
+            <times>2 + 5 * $rank</times>
+            <actionRef label="a1">
+                <param>$rand</param>
+            </actionRef>
+        </repeat>
+        <fireRef label="f1">
+            <param>0.5</param>
+        </fireRef>
+    </action>
+    <action label="a1">
+        <changeSpeed>
+            <speed type="relative">$1</speed>
+            <term>60-$rank*50</term>
+        </changeSpeed>
+        <changeDirection>
+            <direction type="sequence">-3</direction>
+            <term>10</term>
+        </changeDirection>
+        <accel>
+            <horizontal type="relative">0.1</horizontal>
+            <vertical type="absolute">-0.1</vertical>
+            <term>5</term>
+        </accel>
+        <wait>1</wait>
+    </action>
+    <fire label="f1">
+        <direction type="relative">$1 * 90</direction>
+        <speed type="sequence">0</speed>
+        <bullet label="b1">
+            <direction type="absolute">0</direction>
+            <speed type="absolute">1</speed>
+            <action />
+        </bullet>
+    </fire>
+</bulletml>"##,
+            )
+            .unwrap();
+        let xml = bml.to_xml_string();
+        let reparsed = BulletMLParser::new().parse(&xml).unwrap();
+        assert_eq!(reparsed.to_xml_string(), xml);
+        assert_eq!(reparsed.arena.len(), bml.arena.len());
+        for label in bml.action_refs.keys() {
+            assert!(reparsed.action_refs.contains_key(label));
+        }
+        assert!(reparsed.bullet_refs.contains_key("b1"));
+        assert!(reparsed.fire_refs.contains_key("f1"));
+    }
+
+    #[test]
+    fn test_custom_element() {
+        let xml = r##"<?xml version="1.0" ?>
+<bulletml>
+    <action label="top">
+        <shake duration="10" note="&lt;big&gt;">
+            <param>$rank * 2</param>
+            <param>3</param>
+        </shake>
+        <shake />
+    </action>
+</bulletml>
+"##;
+        let parse = |xml: &str| {
+            BulletMLParser::new()
+                .custom_element("shake")
+                .parse(xml)
+                .unwrap()
+        };
+        assert_eq!(parse(xml).to_xml_string(), xml);
+    }
+}